@@ -0,0 +1,146 @@
+#![allow(unused)]
+
+use crate::elf::*;
+
+pub const AT_NULL : usize = 0;
+pub const AT_IGNORE : usize = 1;
+pub const AT_EXECFD : usize = 2;
+pub const AT_PHDR : usize = 3;
+pub const AT_PHENT : usize = 4;
+pub const AT_PHNUM : usize = 5;
+pub const AT_PAGESZ : usize = 6;
+pub const AT_BASE : usize = 7;
+pub const AT_FLAGS : usize = 8;
+pub const AT_ENTRY : usize = 9;
+pub const AT_UID : usize = 11;
+pub const AT_EUID : usize = 12;
+pub const AT_GID : usize = 13;
+pub const AT_EGID : usize = 14;
+pub const AT_PLATFORM : usize = 15;
+pub const AT_HWCAP : usize = 16;
+pub const AT_CLKTCK : usize = 17;
+pub const AT_SECURE : usize = 23;
+pub const AT_BASE_PLATFORM : usize = 24;
+pub const AT_RANDOM : usize = 25;
+pub const AT_HWCAP2 : usize = 26;
+pub const AT_EXECFN : usize = 31;
+pub const AT_SYSINFO_EHDR : usize = 33;
+pub const AT_MINSIGSTKSZ : usize = 51;
+
+const AT_LIMIT : usize = 64;
+const DEFAULT_PAGE_SIZE : usize = 4096;
+const DEFAULT_MINSIGSTKSZ : usize = 2048;
+
+// The vector is decoded once into a table indexed by type, so every lookup
+// afterwards is a load instead of a walk over the initial stack.
+static mut VALUES : [usize; AT_LIMIT] = [0; AT_LIMIT];
+static mut PRESENT : u64 = 0;
+
+/// Decode the auxiliary vector starting at `auxv`, a list of `(type, value)`
+/// pairs terminated by `AT_NULL`. Types we do not know about are skipped.
+pub unsafe fn init(mut auxv: *const usize) {
+    PRESENT = 0;
+    while *auxv != AT_NULL {
+        let key = *auxv;
+        if key < AT_LIMIT {
+            VALUES[key] = *auxv.add(1);
+            PRESENT |= 1 << key;
+        }
+        auxv = auxv.add(2);
+    }
+}
+
+pub fn get(key: usize) -> Option<usize> {
+    unsafe {
+        if key < AT_LIMIT && PRESENT & (1 << key) != 0 {
+            Some(VALUES[key])
+        } else {
+            None
+        }
+    }
+}
+
+pub fn page_size() -> usize {
+    get(AT_PAGESZ).unwrap_or(DEFAULT_PAGE_SIZE)
+}
+
+pub fn hwcap() -> usize {
+    get(AT_HWCAP).unwrap_or(0)
+}
+
+pub fn hwcap2() -> usize {
+    get(AT_HWCAP2).unwrap_or(0)
+}
+
+/// The 16 random bytes the kernel placed on the initial stack.
+pub fn random() -> Option<&'static [u8; 16]> {
+    get(AT_RANDOM).map(|addr| unsafe { &*(addr as *const [u8; 16]) })
+}
+
+/// Base address of the vDSO ELF image.
+pub fn sysinfo_ehdr() -> Option<usize> {
+    get(AT_SYSINFO_EHDR).filter(|&addr| addr != 0)
+}
+
+/// Program headers of the running executable.
+pub fn program_headers() -> &'static [Elf64Phdr] {
+    match (get(AT_PHDR), get(AT_PHNUM)) {
+        (Some(phdr), Some(phnum)) if phdr != 0 => unsafe {
+            core::slice::from_raw_parts(phdr as *const Elf64Phdr, phnum)
+        },
+        _ => &[]
+    }
+}
+
+/// Difference between the addresses the executable was linked at and the
+/// addresses it was loaded at; zero unless it is position independent.
+pub fn load_bias() -> usize {
+    let phdr = get(AT_PHDR).unwrap_or(0);
+    program_headers()
+        .iter()
+        .find(|header| header.p_type == PT_PHDR)
+        .map(|header| phdr.wrapping_sub(header.p_vaddr as usize))
+        .unwrap_or(0)
+}
+
+/// Whether the process runs in secure-execution mode (setuid and friends).
+pub fn secure() -> bool {
+    get(AT_SECURE).unwrap_or(0) != 0
+}
+
+/// Minimal signal stack size required by the kernel for this CPU.
+pub fn min_signal_stack_size() -> usize {
+    get(AT_MINSIGSTKSZ).unwrap_or(DEFAULT_MINSIGSTKSZ)
+}
+
+/// Pathname used to execute the program.
+pub fn execfn() -> Option<&'static [u8]> {
+    get(AT_EXECFN)
+        .filter(|&addr| addr != 0)
+        .map(|addr| unsafe { crate::env::c_str(addr as *const u8) })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::convert::TryInto;
+
+    #[test]
+    fn test_auxv() {
+        let bytes = std::fs::read("/proc/self/auxv").unwrap();
+        let words: Vec<usize> = bytes
+            .chunks_exact(8)
+            .map(|chunk| usize::from_ne_bytes(chunk.try_into().unwrap()))
+            .collect();
+        unsafe {
+            init(words.as_ptr());
+        }
+        assert!(page_size().is_power_of_two());
+        assert!(random().is_some());
+        assert!(sysinfo_ehdr().is_some());
+        assert!(program_headers().iter().any(|header| header.p_type == PT_LOAD));
+        assert!(min_signal_stack_size() > 0);
+        assert!(!execfn().unwrap().is_empty());
+        assert_eq!(get(AT_NULL), None);
+    }
+}
@@ -0,0 +1,228 @@
+use core::alloc::{GlobalAlloc, Layout};
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicU32, AtomicU64};
+use crate::memory::{NAIVE_ALLOC, SEGMENT_ALLOC};
+use crate::sync::Futex;
+
+// Every block is laid out as [front canary][user data][back canary], where the
+// front canary also pads the user data to its alignment.
+const CANARY_SIZE : usize = 16;
+const CANARY : u8 = 0xFD;
+const ALLOC_FILL : u8 = 0xAA;
+const FREE_FILL : u8 = 0xDD;
+
+/// Checking wrapper around `SEGMENT_ALLOC`: red-zone canaries around every
+/// block, poisoned fresh and freed memory, and a table of blocks to catch
+/// double frees and frees of pointers it never handed out.
+pub struct DebugAllocator;
+
+#[cfg_attr(not(any(test, feature = "leak-report")), global_allocator)]
+pub static DEBUG_ALLOC: DebugAllocator = DebugAllocator;
+
+fn front(layout: &Layout) -> usize {
+    layout.align().max(CANARY_SIZE)
+}
+
+unsafe fn inner_layout(layout: &Layout) -> Layout {
+    Layout::from_size_align_unchecked(front(layout) + layout.size() + CANARY_SIZE, layout.align())
+}
+
+// `align` is zero once the block has been freed, so the address is still
+// recognised until it is handed out again.
+struct Entry {
+    ptr: usize,
+    size: usize,
+    align: usize,
+}
+
+/// Open addressing table keyed by the user pointer. It lives outside the heap
+/// it checks, in memory from `NAIVE_ALLOC`.
+struct Table {
+    entries: *mut Entry,
+    capacity: usize,
+    used: usize,
+}
+
+impl Table {
+    unsafe fn slot(&self, ptr: usize) -> *mut Entry {
+        let mask = self.capacity - 1;
+        // pointers are at least 16-byte aligned, mix the higher bits in
+        let mut idx = (ptr >> 4).wrapping_mul(0x9E37_79B9_7F4A_7C15) >> 32 & mask;
+        loop {
+            let entry = self.entries.add(idx);
+            if (*entry).ptr == ptr || (*entry).ptr == 0 {
+                return entry;
+            }
+            idx = (idx + 1) & mask;
+        }
+    }
+
+    unsafe fn find(&self, ptr: usize) -> Option<&mut Entry> {
+        if self.capacity == 0 {
+            return None;
+        }
+        let entry = &mut *self.slot(ptr);
+        if entry.ptr == 0 { None } else { Some(entry) }
+    }
+
+    unsafe fn insert(&mut self, ptr: usize, layout: &Layout) {
+        if (self.used + 1) * 2 > self.capacity {
+            self.grow();
+        }
+        let entry = &mut *self.slot(ptr);
+        if entry.ptr == 0 {
+            self.used += 1;
+        }
+        *entry = Entry { ptr, size: layout.size(), align: layout.align() };
+    }
+
+    unsafe fn grow(&mut self) {
+        let capacity = (self.capacity * 2).max(4096);
+        let layout = Layout::array::<Entry>(capacity).unwrap();
+        let entries = NAIVE_ALLOC.alloc(layout) as *mut Entry;
+        if entries.is_null() {
+            panic!("debug allocator: unable to grow the block table");
+        }
+        let old = Table { entries: self.entries, capacity: self.capacity, used: self.used };
+        self.entries = entries;
+        self.capacity = capacity;
+        for idx in 0..old.capacity {
+            let entry = old.entries.add(idx);
+            if (*entry).ptr != 0 {
+                core::ptr::write(self.slot((*entry).ptr), core::ptr::read(entry));
+            }
+        }
+        if old.capacity != 0 {
+            NAIVE_ALLOC.dealloc(old.entries as *mut u8, Layout::array::<Entry>(old.capacity).unwrap());
+        }
+    }
+}
+
+static BLOCKS: Futex<Table> = Futex {
+    _flag: AtomicU64::new(0),
+    _elision: AtomicU32::new(0),
+    item: UnsafeCell::new(Table {
+        entries: core::ptr::null_mut(),
+        capacity: 0,
+        used: 0,
+    })
+};
+
+enum Error {
+    DoubleFree,
+    Foreign,
+    Mismatch(usize, usize),
+}
+
+unsafe impl GlobalAlloc for DebugAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let base = SEGMENT_ALLOC.alloc(inner_layout(&layout));
+        if base.is_null() {
+            return base;
+        }
+        let front = front(&layout);
+        let ptr = base.add(front);
+        core::ptr::write_bytes(base, CANARY, front);
+        core::ptr::write_bytes(ptr, ALLOC_FILL, layout.size());
+        core::ptr::write_bytes(ptr.add(layout.size()), CANARY, CANARY_SIZE);
+        BLOCKS.lock().insert(ptr as usize, &layout);
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let error = match BLOCKS.lock().find(ptr as usize) {
+            Some(entry) if entry.align == 0 => Some(Error::DoubleFree),
+            Some(entry) if entry.size != layout.size() || entry.align != layout.align() => {
+                Some(Error::Mismatch(entry.size, entry.align))
+            }
+            Some(entry) => {
+                entry.align = 0;
+                None
+            }
+            None => Some(Error::Foreign)
+        };
+        match error {
+            Some(Error::DoubleFree) => panic!("double free of {:p} with {:?}", ptr, layout),
+            Some(Error::Foreign) => panic!("free of foreign pointer {:p} with {:?}", ptr, layout),
+            Some(Error::Mismatch(size, align)) => panic!(
+                "free of {:p} with {:?}, but it was allocated with size {} and align {}",
+                ptr, layout, size, align),
+            None => ()
+        }
+        let front = front(&layout);
+        let base = ptr.sub(front);
+        let before = core::slice::from_raw_parts(base, front);
+        let after = core::slice::from_raw_parts(ptr.add(layout.size()), CANARY_SIZE);
+        if before.iter().any(|&byte| byte != CANARY) {
+            panic!("heap corruption: canary before {:p} clobbered, {:?}", ptr, layout);
+        }
+        if after.iter().any(|&byte| byte != CANARY) {
+            panic!("heap corruption: canary after {:p} clobbered, {:?}", ptr, layout);
+        }
+        let inner = inner_layout(&layout);
+        core::ptr::write_bytes(base, FREE_FILL, inner.size());
+        SEGMENT_ALLOC.dealloc(base, inner);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_poison() {
+        unsafe {
+            let layout = Layout::from_size_align(100, 64).unwrap();
+            let ptr = DEBUG_ALLOC.alloc(layout);
+            assert_eq!(ptr as usize % 64, 0);
+            assert!(core::slice::from_raw_parts(ptr, 100).iter().all(|&byte| byte == ALLOC_FILL));
+            DEBUG_ALLOC.dealloc(ptr, layout);
+            assert!(core::slice::from_raw_parts(ptr, 100).iter().skip(8).all(|&byte| byte == FREE_FILL));
+            // many live blocks grow the table
+            let blocks: std::vec::Vec<_> = (0..10000).map(|_| DEBUG_ALLOC.alloc(layout)).collect();
+            for ptr in blocks {
+                DEBUG_ALLOC.dealloc(ptr, layout);
+            }
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "double free")]
+    fn test_double_free() {
+        unsafe {
+            let layout = Layout::from_size_align(24, 8).unwrap();
+            let ptr = DEBUG_ALLOC.alloc(layout);
+            DEBUG_ALLOC.dealloc(ptr, layout);
+            DEBUG_ALLOC.dealloc(ptr, layout);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "foreign pointer")]
+    fn test_foreign() {
+        unsafe {
+            let mut local = [0u8; 32];
+            DEBUG_ALLOC.dealloc(local.as_mut_ptr(), Layout::from_size_align(32, 1).unwrap());
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "canary after")]
+    fn test_overflow() {
+        unsafe {
+            let layout = Layout::from_size_align(24, 8).unwrap();
+            let ptr = DEBUG_ALLOC.alloc(layout);
+            *ptr.add(24) = 0;
+            DEBUG_ALLOC.dealloc(ptr, layout);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "allocated with size 24")]
+    fn test_mismatch() {
+        unsafe {
+            let ptr = DEBUG_ALLOC.alloc(Layout::from_size_align(24, 8).unwrap());
+            DEBUG_ALLOC.dealloc(ptr, Layout::from_size_align(32, 8).unwrap());
+        }
+    }
+}
@@ -0,0 +1,80 @@
+#![allow(unused)]
+
+pub const PT_NULL : u32 = 0;
+pub const PT_LOAD : u32 = 1;
+pub const PT_DYNAMIC : u32 = 2;
+pub const PT_INTERP : u32 = 3;
+pub const PT_NOTE : u32 = 4;
+pub const PT_PHDR : u32 = 6;
+pub const PT_TLS : u32 = 7;
+
+#[repr(C)]
+pub struct Elf64Phdr {
+    pub p_type: u32,
+    pub p_flags: u32,
+    pub p_offset: u64,
+    pub p_vaddr: u64,
+    pub p_paddr: u64,
+    pub p_filesz: u64,
+    pub p_memsz: u64,
+    pub p_align: u64,
+}
+
+pub const ELFMAG : [u8; 4] = [0x7f, b'E', b'L', b'F'];
+pub const ELFCLASS64 : u8 = 2;
+pub const EI_CLASS : usize = 4;
+
+pub const DT_NULL : i64 = 0;
+pub const DT_HASH : i64 = 4;
+pub const DT_STRTAB : i64 = 5;
+pub const DT_SYMTAB : i64 = 6;
+pub const DT_GNU_HASH : i64 = 0x6ffffef5;
+
+pub const STB_GLOBAL : u8 = 1;
+pub const STB_WEAK : u8 = 2;
+pub const STT_FUNC : u8 = 2;
+pub const SHN_UNDEF : u16 = 0;
+
+#[repr(C)]
+pub struct Elf64Ehdr {
+    pub e_ident: [u8; 16],
+    pub e_type: u16,
+    pub e_machine: u16,
+    pub e_version: u32,
+    pub e_entry: u64,
+    pub e_phoff: u64,
+    pub e_shoff: u64,
+    pub e_flags: u32,
+    pub e_ehsize: u16,
+    pub e_phentsize: u16,
+    pub e_phnum: u16,
+    pub e_shentsize: u16,
+    pub e_shnum: u16,
+    pub e_shstrndx: u16,
+}
+
+#[repr(C)]
+pub struct Elf64Dyn {
+    pub d_tag: i64,
+    pub d_val: u64,
+}
+
+#[repr(C)]
+pub struct Elf64Sym {
+    pub st_name: u32,
+    pub st_info: u8,
+    pub st_other: u8,
+    pub st_shndx: u16,
+    pub st_value: u64,
+    pub st_size: u64,
+}
+
+impl Elf64Sym {
+    pub fn binding(&self) -> u8 {
+        self.st_info >> 4
+    }
+
+    pub fn symbol_type(&self) -> u8 {
+        self.st_info & 0xf
+    }
+}
@@ -0,0 +1,136 @@
+use core::iter::FusedIterator;
+
+// argc, argv and envp as the kernel laid them out on the initial stack,
+// recorded once by `runtime::_start` before anything else runs.
+static mut ARGC: usize = 0;
+static mut ARGV: *const *const u8 = core::ptr::null();
+static mut ENVP: *const *const u8 = core::ptr::null();
+
+pub unsafe fn init(argc: usize, argv: *const *const u8, envp: *const *const u8) {
+    ARGC = argc;
+    ARGV = argv;
+    ENVP = envp;
+}
+
+pub(crate) unsafe fn c_str(ptr: *const u8) -> &'static [u8] {
+    let mut len = 0;
+    while *ptr.add(len) != 0 {
+        len += 1;
+    }
+    core::slice::from_raw_parts(ptr, len)
+}
+
+pub struct Args {
+    cursor: usize,
+    end: usize,
+}
+
+impl Iterator for Args {
+    type Item = &'static [u8];
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.cursor == self.end {
+            return None;
+        }
+        let arg = unsafe { c_str(*ARGV.add(self.cursor)) };
+        self.cursor += 1;
+        Some(arg)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let len = self.end - self.cursor;
+        (len, Some(len))
+    }
+}
+
+impl DoubleEndedIterator for Args {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        if self.cursor == self.end {
+            return None;
+        }
+        self.end -= 1;
+        Some(unsafe { c_str(*ARGV.add(self.end)) })
+    }
+}
+
+impl ExactSizeIterator for Args {}
+
+impl FusedIterator for Args {}
+
+/// Iterate over the command line, program name included.
+pub fn args() -> Args {
+    Args {
+        cursor: 0,
+        end: unsafe { ARGC },
+    }
+}
+
+pub struct Vars {
+    cursor: *const *const u8,
+}
+
+impl Iterator for Vars {
+    type Item = (&'static [u8], &'static [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        unsafe {
+            if self.cursor.is_null() || (*self.cursor).is_null() {
+                return None;
+            }
+            let entry = c_str(*self.cursor);
+            self.cursor = self.cursor.add(1);
+            // entries without '=' are tolerated as a name with an empty value
+            Some(match entry.iter().position(|&c| c == b'=') {
+                Some(split) => (&entry[..split], &entry[split + 1..]),
+                None => (entry, &entry[entry.len()..]),
+            })
+        }
+    }
+}
+
+impl FusedIterator for Vars {}
+
+/// Iterate over the environment as `(name, value)` pairs.
+pub fn env() -> Vars {
+    Vars {
+        cursor: unsafe { ENVP },
+    }
+}
+
+/// Look up a single environment variable.
+pub fn var<K: AsRef<[u8]>>(name: K) -> Option<&'static [u8]> {
+    let name = name.as_ref();
+    env().find(|(key, _)| *key == name).map(|(_, value)| value)
+}
+
+#[cfg(test)]
+mod test {
+    #[test]
+    fn test_args_env() {
+        let argv = [
+            b"prog\0".as_ptr(),
+            b"-v\0".as_ptr(),
+            b"input\0".as_ptr(),
+            core::ptr::null(),
+        ];
+        let envp = [
+            b"HOME=/root\0".as_ptr(),
+            b"EMPTY=\0".as_ptr(),
+            b"PAIR=a=b\0".as_ptr(),
+            core::ptr::null(),
+        ];
+        unsafe {
+            super::init(3, argv.as_ptr(), envp.as_ptr());
+        }
+        let args: Vec<_> = super::args().collect();
+        assert_eq!(args, [&b"prog"[..], b"-v", b"input"]);
+        assert_eq!(super::args().len(), 3);
+        assert_eq!(super::args().rev().next(), Some(&b"input"[..]));
+        assert_eq!(super::env().count(), 3);
+        assert_eq!(super::var("HOME"), Some(&b"/root"[..]));
+        assert_eq!(super::var("EMPTY"), Some(&b""[..]));
+        assert_eq!(super::var("PAIR"), Some(&b"a=b"[..]));
+        assert_eq!(super::var("HOM"), None);
+        assert_eq!(super::var("MISSING"), None);
+    }
+}
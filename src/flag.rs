@@ -1,10 +1,19 @@
 #![allow(unused)]
 
+pub const PROT_NONE : u64 = 0x0;
 pub const PROT_READ : u64 = 0x1;
 pub const PROT_WRITE : u64 = 0x2;
 pub const MAP_ANON : u64 = 0x20;
 pub const MAP_PRIVATE: u64 = 0x02;
 pub const MAP_SHARED: u64 = 0x01;
+pub const MAP_STACK: u64 = 0x20000;
+pub const MAP_HUGETLB: u64 = 0x40000;
+pub const MPOL_PREFERRED : u64 = 1;
+pub const MREMAP_MAYMOVE : u64 = 1;
+pub const MREMAP_FIXED : u64 = 2;
+pub const MADV_DONTNEED : u64 = 4;
+pub const MADV_FREE : u64 = 8;
+pub const MADV_HUGEPAGE : u64 = 14;
 pub const CSIGNAL : u64 = 0x000000ff;	
 pub const CLONE_VM : u64 = 0x00000100;	
 pub const CLONE_FS : u64 = 0x00000200;	
@@ -0,0 +1,307 @@
+use core::alloc::{GlobalAlloc, Layout};
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicU32, AtomicU64};
+#[cfg(feature = "debug-alloc")]
+use crate::debug_alloc::DEBUG_ALLOC as INNER;
+#[cfg(not(feature = "debug-alloc"))]
+use crate::memory::SEGMENT_ALLOC as INNER;
+use crate::memory::NAIVE_ALLOC;
+use crate::sync::Futex;
+use crate::write::EWRITER;
+
+/// Allocator wrapper that keeps every live block on a list together with its
+/// layout and the address it was allocated from, so whatever is still live at
+/// exit can be reported per call site.
+///
+/// Call sites are found by walking the frame pointer chain; build with
+/// `-C force-frame-pointers=yes` or most of them end up as `unknown`.
+pub struct LeakTracker;
+
+#[cfg_attr(not(test), global_allocator)]
+pub static LEAK_ALLOC: LeakTracker = LeakTracker;
+
+// frames to skip above the one running `alloc`: the library helper behind
+// `Box::new` or `Vec::with_capacity`, so the site is the code that asked
+const CALLER_DEPTH : usize = 1;
+
+/// Sits right before every tracked block.
+#[repr(C)]
+struct Header {
+    prev: *mut Header,
+    next: *mut Header,
+    size: usize,
+    align: usize,
+    caller: usize,
+}
+
+const HEADER_SIZE : usize = core::mem::size_of::<Header>();
+
+fn front(layout: &Layout) -> usize {
+    (HEADER_SIZE + layout.align() - 1) & !(layout.align() - 1)
+}
+
+unsafe fn inner_layout(layout: &Layout) -> Layout {
+    Layout::from_size_align_unchecked(front(layout) + layout.size(), layout.align())
+}
+
+struct Live {
+    first: *mut Header,
+}
+
+static LIVE: Futex<Live> = Futex {
+    _flag: AtomicU64::new(0),
+    _elision: AtomicU32::new(0),
+    item: UnsafeCell::new(Live {
+        first: core::ptr::null_mut(),
+    })
+};
+
+#[cfg(not(test))]
+unsafe fn stack_top() -> usize {
+    crate::thread::stack_top()
+}
+
+// test threads are not ours, ask the C library where their stack ends
+#[cfg(test)]
+unsafe fn stack_top() -> usize {
+    extern "C" {
+        fn pthread_self() -> usize;
+        fn pthread_getattr_np(thread: usize, attr: *mut [u64; 8]) -> i32;
+        fn pthread_attr_getstack(attr: *const [u64; 8], addr: *mut usize, size: *mut usize) -> i32;
+        fn pthread_attr_destroy(attr: *mut [u64; 8]) -> i32;
+    }
+    thread_local! {
+        static TOP: usize = unsafe {
+            let mut attr = [0u64; 8];
+            let (mut addr, mut size) = (0, 0);
+            if pthread_getattr_np(pthread_self(), &mut attr) != 0 {
+                0
+            } else {
+                let top = if pthread_attr_getstack(&attr, &mut addr, &mut size) == 0 { addr + size } else { 0 };
+                pthread_attr_destroy(&mut attr);
+                top
+            }
+        };
+    }
+    TOP.with(|top| *top)
+}
+
+/// Whether a saved frame record `frame` lies within the stack between `bottom`
+/// and `top`.
+fn on_stack(frame: usize, bottom: usize, top: usize) -> bool {
+    frame & 15 == 0 && frame >= bottom && frame < top && top - frame >= 16
+}
+
+/// Return address `CALLER_DEPTH` frames up from the caller, 0 if the chain
+/// leaves the stack of the calling thread.
+#[inline(always)]
+unsafe fn caller() -> usize {
+    let mut frame: usize;
+    let bottom: usize;
+    asm!("mov {}, rbp\n mov {}, rsp", out(reg) frame, out(reg) bottom);
+    let top = stack_top();
+    for _ in 0..CALLER_DEPTH {
+        if !on_stack(frame, bottom, top) {
+            return 0;
+        }
+        let up = *(frame as *const usize);
+        if up <= frame {
+            return 0;
+        }
+        frame = up;
+    }
+    if !on_stack(frame, bottom, top) {
+        return 0;
+    }
+    *(frame as *const usize).add(1)
+}
+
+impl LeakTracker {
+    unsafe fn alloc_from(&self, layout: Layout, caller: usize) -> *mut u8 {
+        let base = INNER.alloc(inner_layout(&layout));
+        if base.is_null() {
+            return base;
+        }
+        let ptr = base.add(front(&layout));
+        let header = (ptr as *mut Header).sub(1);
+        let mut live = LIVE.lock();
+        header.write(Header {
+            prev: core::ptr::null_mut(),
+            next: live.first,
+            size: layout.size(),
+            align: layout.align(),
+            caller,
+        });
+        if !live.first.is_null() {
+            (*live.first).prev = header;
+        }
+        live.first = header;
+        ptr
+    }
+}
+
+unsafe impl GlobalAlloc for LeakTracker {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.alloc_from(layout, caller())
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let header = (ptr as *mut Header).sub(1);
+        {
+            let mut live = LIVE.lock();
+            if (*header).prev.is_null() {
+                live.first = (*header).next;
+            } else {
+                (*(*header).prev).next = (*header).next;
+            }
+            if !(*header).next.is_null() {
+                (*(*header).next).prev = (*header).prev;
+            }
+        }
+        INNER.dealloc(ptr.sub(front(&layout)), inner_layout(&layout));
+    }
+}
+
+#[derive(Copy, Clone)]
+struct Site {
+    caller: usize,
+    blocks: usize,
+    bytes: usize,
+}
+
+/// Group the live blocks by call site, largest first, and hand them to `f`.
+/// The sites live in memory from `NAIVE_ALLOC` so the grouping does not show
+/// up in its own result.
+unsafe fn with_sites<F: FnOnce(&[Site])>(f: F) {
+    let live = LIVE.lock();
+    let mut count = 0usize;
+    let mut header = live.first;
+    while !header.is_null() {
+        count += 1;
+        header = (*header).next;
+    }
+    if count == 0 {
+        drop(live);
+        return f(&[]);
+    }
+    let capacity = (count * 2).next_power_of_two();
+    let layout = Layout::array::<Site>(capacity).unwrap();
+    let table = NAIVE_ALLOC.alloc_zeroed(layout) as *mut Site;
+    if table.is_null() {
+        drop(live);
+        return f(&[]);
+    }
+    let sites = core::slice::from_raw_parts_mut(table, capacity);
+    let mut header = live.first;
+    while !header.is_null() {
+        // caller + 1 keeps `unknown` apart from empty slots
+        let key = (*header).caller + 1;
+        let mut idx = key.wrapping_mul(0x9E37_79B9_7F4A_7C15) >> 32 & (capacity - 1);
+        while sites[idx].caller != 0 && sites[idx].caller != key {
+            idx = (idx + 1) & (capacity - 1);
+        }
+        sites[idx].caller = key;
+        sites[idx].blocks += 1;
+        sites[idx].bytes += (*header).size;
+        header = (*header).next;
+    }
+    drop(live);
+    sites.sort_unstable_by(|a, b| b.bytes.cmp(&a.bytes).then(b.blocks.cmp(&a.blocks)));
+    let used = sites.iter().take_while(|site| site.caller != 0).count();
+    for site in sites[..used].iter_mut() {
+        site.caller -= 1;
+    }
+    f(&sites[..used]);
+    NAIVE_ALLOC.dealloc(table as *mut u8, layout);
+}
+
+/// Print the blocks still live, grouped by the address they were allocated
+/// from. Addresses can be resolved with `addr2line -e <binary>`.
+pub fn report() {
+    use core::fmt::Write;
+    unsafe {
+        with_sites(|sites| {
+            if sites.is_empty() {
+                return;
+            }
+            let blocks: usize = sites.iter().map(|site| site.blocks).sum();
+            let bytes: usize = sites.iter().map(|site| site.bytes).sum();
+            let mut out = EWRITER.lock();
+            let _ = writeln!(out, "leaked {} bytes in {} blocks from {} call sites",
+                             bytes, blocks, sites.len());
+            for site in sites {
+                if site.caller == 0 {
+                    let _ = writeln!(out, "  {:>18}: {} bytes in {} blocks", "unknown", site.bytes, site.blocks);
+                } else {
+                    let _ = writeln!(out, "  {:#18x}: {} bytes in {} blocks", site.caller, site.bytes, site.blocks);
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_leak_sites() {
+        unsafe {
+            let layout = Layout::from_size_align(40, 8).unwrap();
+            let wide = Layout::from_size_align(100, 256).unwrap();
+            let blocks: std::vec::Vec<_> = (0..3).map(|_| LEAK_ALLOC.alloc(layout)).collect();
+            let aligned = LEAK_ALLOC.alloc(wide);
+            assert_eq!(aligned as usize % 256, 0);
+            let mut seen = (0, 0);
+            with_sites(|sites| {
+                seen = (sites.iter().map(|site| site.blocks).sum(),
+                        sites.iter().map(|site| site.bytes).sum());
+            });
+            // other tests in this module may hold blocks of their own
+            assert!(seen.0 >= 4 && seen.1 >= 220);
+            LEAK_ALLOC.dealloc(aligned, wide);
+            for ptr in blocks {
+                LEAK_ALLOC.dealloc(ptr, layout);
+            }
+        }
+    }
+
+    // test builds keep no frame pointers, so each site names itself
+    #[inline(never)]
+    unsafe fn first_site(layout: Layout) -> *mut u8 {
+        LEAK_ALLOC.alloc_from(layout, first_site as usize)
+    }
+
+    #[inline(never)]
+    unsafe fn second_site(layout: Layout) -> *mut u8 {
+        LEAK_ALLOC.alloc_from(layout, second_site as usize)
+    }
+
+    #[test]
+    fn test_leak_attribution() {
+        unsafe {
+            let layout = Layout::from_size_align(24, 8).unwrap();
+            let freed = first_site(layout);
+            let leaked = second_site(layout);
+            LEAK_ALLOC.dealloc(freed, layout);
+            let mut seen = (None, None);
+            with_sites(|sites| {
+                let find = |caller: usize| sites.iter().find(|site| site.caller == caller)
+                    .map(|site| (site.blocks, site.bytes));
+                seen = (find(first_site as usize), find(second_site as usize));
+            });
+            assert_eq!(seen, (None, Some((1, 24))));
+            LEAK_ALLOC.dealloc(leaked, layout);
+        }
+    }
+
+    #[test]
+    fn test_caller_bounds() {
+        unsafe {
+            // whatever the frame chain holds, the walk stays on this stack
+            let top = stack_top();
+            assert!(top != 0);
+            let _ = caller();
+        }
+    }
+}
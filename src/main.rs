@@ -7,6 +7,7 @@
 #![feature(alloc_prelude)]
 #![feature(llvm_asm)]
 #![feature(asm)]
+#![feature(naked_functions)]
 
 
 extern crate alloc;
@@ -14,9 +15,18 @@ use syscalls::*;
 use core::fmt::Write;
 
 mod flag;
+mod env;
+mod elf;
+mod auxv;
+mod time;
+mod vdso;
 mod write;
 mod sync;
 mod memory;
+#[cfg(feature = "debug-alloc")]
+mod debug_alloc;
+#[cfg(feature = "leak-report")]
+mod leak;
 mod thread;
 #[cfg(not(test))]
 mod runtime;
@@ -26,8 +36,11 @@ extern crate test;
 
 #[cfg(not(test))]
 #[no_mangle]
-fn main() -> i32 {
+extern "C" fn main(_argc: isize, _argv: *const *const u8, _envp: *const *const u8) -> isize {
     let s = unsafe { thread::thread_self() };
     println!("{}", s.ppid);
+    for arg in env::args() {
+        println!("{}", core::str::from_utf8(arg).unwrap_or("<non-utf8>"));
+    }
     0
 }
@@ -4,12 +4,15 @@ use crate::flag;
 use core::alloc::Layout;
 use crate::write::{WRITER, EWRITER};
 use core::sync::atomic::*;
+use core::cell::UnsafeCell;
+use crate::sync::Futex;
 
 const NUMA_LIMIT : usize = 256;
 
+/// One mapping per allocation. Only used for runtime structures that must
+/// not depend on a heap, such as thread control blocks.
 pub struct NaiveAllocator;
 
-#[cfg_attr(not(test), global_allocator)]
 pub static NAIVE_ALLOC: NaiveAllocator = NaiveAllocator;
 
 unsafe impl core::alloc::GlobalAlloc for NaiveAllocator {
@@ -19,7 +22,9 @@ unsafe impl core::alloc::GlobalAlloc for NaiveAllocator {
             0,
             layout.size(),
             flag::PROT_READ | flag::PROT_WRITE,
-            flag::MAP_PRIVATE | flag::MAP_ANON
+            flag::MAP_PRIVATE | flag::MAP_ANON,
+            -1i64,
+            0
         ).unwrap_or(0) as *mut u8
     }
 
@@ -30,42 +35,323 @@ unsafe impl core::alloc::GlobalAlloc for NaiveAllocator {
     }
 }
 
-#[cfg(not(test))]
-#[no_mangle]
-unsafe extern "C" fn memcpy(dst: *mut u8,
-                            src: *const u8,
-                            size: usize) -> *mut u8 {
+// copies at least this long bypass the cache: they would only evict the
+// caller's working set, roughly a last level cache worth of it
+const NON_TEMPORAL_THRESHOLD : usize = 4 << 20;
+// shorter runs lose to a vector loop on the startup cost of `rep movsb`
+const REP_THRESHOLD : usize = 2048;
+
+// 0 until probed, then 1 without and 2 with enhanced `rep movsb`/`rep stosb`
+static ERMS: AtomicU8 = AtomicU8::new(0);
+
+fn has_erms() -> bool {
+    use core::arch::x86_64::*;
+    match ERMS.load(Ordering::Relaxed) {
+        0 => {
+            let erms = unsafe {
+                __cpuid(0).eax >= 7 && __cpuid_count(7, 0).ebx & (1 << 9) != 0
+            };
+            ERMS.store(if erms { 2 } else { 1 }, Ordering::Relaxed);
+            erms
+        }
+        state => state == 2
+    }
+}
+
+// Copies of up to 32 bytes load both ends before storing anything, so they
+// are also correct for overlapping ranges.
+#[inline(always)]
+unsafe fn copy_small(dst: *mut u8, src: *const u8, size: usize) {
+    use core::arch::x86_64::*;
+    if size > 16 {
+        let head = _mm_loadu_si128(src as *const __m128i);
+        let tail = _mm_loadu_si128(src.add(size - 16) as *const __m128i);
+        _mm_storeu_si128(dst as *mut __m128i, head);
+        _mm_storeu_si128(dst.add(size - 16) as *mut __m128i, tail);
+    } else if size >= 8 {
+        let head = (src as *const u64).read_unaligned();
+        let tail = (src.add(size - 8) as *const u64).read_unaligned();
+        (dst as *mut u64).write_unaligned(head);
+        (dst.add(size - 8) as *mut u64).write_unaligned(tail);
+    } else if size >= 4 {
+        let head = (src as *const u32).read_unaligned();
+        let tail = (src.add(size - 4) as *const u32).read_unaligned();
+        (dst as *mut u32).write_unaligned(head);
+        (dst.add(size - 4) as *mut u32).write_unaligned(tail);
+    } else if size >= 2 {
+        let head = (src as *const u16).read_unaligned();
+        let tail = (src.add(size - 2) as *const u16).read_unaligned();
+        (dst as *mut u16).write_unaligned(head);
+        (dst.add(size - 2) as *mut u16).write_unaligned(tail);
+    } else if size == 1 {
+        *dst = *src;
+    }
+}
+
+/// Copy more than 32 bytes with 16-byte moves; the last move overlaps the
+/// one before it instead of falling back to bytes.
+#[inline(always)]
+unsafe fn copy_forward(dst: *mut u8, src: *const u8, size: usize) {
+    use core::arch::x86_64::*;
+    let tail = _mm_loadu_si128(src.add(size - 16) as *const __m128i);
+    let mut i = 0;
+    while i + 16 < size {
+        let v = _mm_loadu_si128(src.add(i) as *const __m128i);
+        _mm_storeu_si128(dst.add(i) as *mut __m128i, v);
+        i += 16;
+    }
+    _mm_storeu_si128(dst.add(size - 16) as *mut __m128i, tail);
+}
+
+/// Copy at least `NON_TEMPORAL_THRESHOLD` bytes around the cache. Streaming
+/// stores need an aligned destination, so the unaligned head and tail go
+/// through ordinary stores.
+unsafe fn copy_stream(dst: *mut u8, src: *const u8, size: usize) {
     use core::arch::x86_64::*;
-    const MASK: usize = 31;
-    let preset = MASK & size;
-    for i in 0..preset {
-        *dst.add(i as usize) = *src.add(i as usize);
+    let head = _mm_loadu_si128(src as *const __m128i);
+    let tail = _mm_loadu_si128(src.add(size - 16) as *const __m128i);
+    _mm_storeu_si128(dst as *mut __m128i, head);
+    let mut i = 16 - (dst as usize & 15);
+    while i + 64 <= size {
+        let a = _mm_loadu_si128(src.add(i) as *const __m128i);
+        let b = _mm_loadu_si128(src.add(i + 16) as *const __m128i);
+        let c = _mm_loadu_si128(src.add(i + 32) as *const __m128i);
+        let d = _mm_loadu_si128(src.add(i + 48) as *const __m128i);
+        _mm_stream_si128(dst.add(i) as *mut __m128i, a);
+        _mm_stream_si128(dst.add(i + 16) as *mut __m128i, b);
+        _mm_stream_si128(dst.add(i + 32) as *mut __m128i, c);
+        _mm_stream_si128(dst.add(i + 48) as *mut __m128i, d);
+        i += 64;
     }
-    for i in (preset..size).step_by(32) {
-        let v = _mm256_loadu_si256(&mut *((src as usize + i) as *mut _));
-        _mm256_stream_si256(&mut *((dst as usize + i) as *mut _), v);
+    while i + 16 <= size {
+        let v = _mm_loadu_si128(src.add(i) as *const __m128i);
+        _mm_stream_si128(dst.add(i) as *mut __m128i, v);
+        i += 16;
+    }
+    // streaming stores are weakly ordered
+    _mm_sfence();
+    _mm_storeu_si128(dst.add(size - 16) as *mut __m128i, tail);
+}
+
+#[cfg_attr(not(test), no_mangle)]
+pub unsafe extern "C" fn memcpy(dst: *mut u8,
+                                src: *const u8,
+                                size: usize) -> *mut u8 {
+    if size <= 32 {
+        copy_small(dst, src, size);
+    } else if size >= NON_TEMPORAL_THRESHOLD {
+        copy_stream(dst, src, size);
+    } else if size >= REP_THRESHOLD && has_erms() {
+        asm!("rep movsb",
+             inout("rcx") size => _,
+             inout("rdi") dst => _,
+             inout("rsi") src => _,
+             options(nostack, preserves_flags));
+    } else {
+        copy_forward(dst, src, size);
     }
     dst
 }
 
-#[cfg(not(test))]
-#[no_mangle]
-unsafe extern "C" fn memset(dst: *mut u8,
-                            value: i8,
-                            size: usize) {
+#[cfg_attr(not(test), no_mangle)]
+pub unsafe extern "C" fn memset(dst: *mut u8,
+                                value: i32,
+                                size: usize) -> *mut u8 {
+    use core::arch::x86_64::*;
+    let byte = value as u8;
+    if size <= 16 {
+        let word = byte as u64 * 0x0101_0101_0101_0101;
+        if size >= 8 {
+            (dst as *mut u64).write_unaligned(word);
+            (dst.add(size - 8) as *mut u64).write_unaligned(word);
+        } else if size >= 4 {
+            (dst as *mut u32).write_unaligned(word as u32);
+            (dst.add(size - 4) as *mut u32).write_unaligned(word as u32);
+        } else if size >= 2 {
+            (dst as *mut u16).write_unaligned(word as u16);
+            (dst.add(size - 2) as *mut u16).write_unaligned(word as u16);
+        } else if size == 1 {
+            *dst = byte;
+        }
+        return dst;
+    }
+    let v = _mm_set1_epi8(byte as i8);
+    if size >= NON_TEMPORAL_THRESHOLD {
+        _mm_storeu_si128(dst as *mut __m128i, v);
+        let mut i = 16 - (dst as usize & 15);
+        while i + 64 <= size {
+            _mm_stream_si128(dst.add(i) as *mut __m128i, v);
+            _mm_stream_si128(dst.add(i + 16) as *mut __m128i, v);
+            _mm_stream_si128(dst.add(i + 32) as *mut __m128i, v);
+            _mm_stream_si128(dst.add(i + 48) as *mut __m128i, v);
+            i += 64;
+        }
+        while i + 16 <= size {
+            _mm_stream_si128(dst.add(i) as *mut __m128i, v);
+            i += 16;
+        }
+        _mm_sfence();
+    } else if size >= REP_THRESHOLD && has_erms() {
+        asm!("rep stosb",
+             inout("rcx") size => _,
+             inout("rdi") dst => _,
+             in("al") byte,
+             options(nostack, preserves_flags));
+        return dst;
+    } else {
+        let mut i = 0;
+        while i + 16 < size {
+            _mm_storeu_si128(dst.add(i) as *mut __m128i, v);
+            i += 16;
+        }
+    }
+    _mm_storeu_si128(dst.add(size - 16) as *mut __m128i, v);
+    dst
+}
+
+/// Copy more than 32 bytes from the end down, for a destination overlapping
+/// the source from above.
+#[inline(always)]
+unsafe fn copy_backward(dst: *mut u8, src: *const u8, size: usize) {
     use core::arch::x86_64::*;
-    const MASK: usize = 31;
-    let preset = MASK & size;
-    for i in 0..preset {
-        *dst.add(i as usize) = 0;
+    let head = _mm_loadu_si128(src as *const __m128i);
+    let mut end = size;
+    while end > 16 {
+        end -= 16;
+        let v = _mm_loadu_si128(src.add(end) as *const __m128i);
+        _mm_storeu_si128(dst.add(end) as *mut __m128i, v);
     }
-    for i in (preset..size).step_by(32) {
-        _mm256_stream_si256(&mut *((dst as usize + i) as *mut _), _mm256_set1_epi8(value));
+    _mm_storeu_si128(dst as *mut __m128i, head);
+}
+
+#[cfg_attr(not(test), no_mangle)]
+pub unsafe extern "C" fn memmove(dst: *mut u8,
+                                 src: *const u8,
+                                 size: usize) -> *mut u8 {
+    if size <= 32 {
+        copy_small(dst, src, size);
+    } else if dst as usize >= src as usize + size || src as usize >= dst as usize + size {
+        memcpy(dst, src, size);
+    } else if (dst as usize) < src as usize {
+        // a forward copy only overwrites source bytes it has already read
+        copy_forward(dst, src, size);
+    } else {
+        copy_backward(dst, src, size);
     }
+    dst
 }
 
+// mask of the 16 bytes at which `a` and `b` differ
+#[inline(always)]
+unsafe fn diff_mask(a: *const u8, b: *const u8) -> u32 {
+    use core::arch::x86_64::*;
+    let x = _mm_loadu_si128(a as *const __m128i);
+    let y = _mm_loadu_si128(b as *const __m128i);
+    !(_mm_movemask_epi8(_mm_cmpeq_epi8(x, y)) as u32) & 0xFFFF
+}
+
+#[cfg_attr(not(test), no_mangle)]
+pub unsafe extern "C" fn memcmp(a: *const u8,
+                                b: *const u8,
+                                size: usize) -> i32 {
+    let byte_diff = |at: usize| *a.add(at) as i32 - *b.add(at) as i32;
+    if size < 16 {
+        // big endian words compare like the bytes they hold
+        if size >= 8 {
+            for &at in [0, size - 8].iter() {
+                let x = (a.add(at) as *const u64).read_unaligned().to_be();
+                let y = (b.add(at) as *const u64).read_unaligned().to_be();
+                if x != y {
+                    return if x < y { -1 } else { 1 };
+                }
+            }
+        } else if size >= 4 {
+            for &at in [0, size - 4].iter() {
+                let x = (a.add(at) as *const u32).read_unaligned().to_be();
+                let y = (b.add(at) as *const u32).read_unaligned().to_be();
+                if x != y {
+                    return if x < y { -1 } else { 1 };
+                }
+            }
+        } else {
+            for at in 0..size {
+                if *a.add(at) != *b.add(at) {
+                    return byte_diff(at);
+                }
+            }
+        }
+        return 0;
+    }
+    let mut i = 0;
+    while i + 16 < size {
+        let mask = diff_mask(a.add(i), b.add(i));
+        if mask != 0 {
+            return byte_diff(i + mask.trailing_zeros() as usize);
+        }
+        i += 16;
+    }
+    let mask = diff_mask(a.add(size - 16), b.add(size - 16));
+    if mask != 0 {
+        return byte_diff(size - 16 + mask.trailing_zeros() as usize);
+    }
+    0
+}
 
+/// Like `memcmp`, but only tells whether the ranges are equal.
+#[cfg_attr(not(test), no_mangle)]
+pub unsafe extern "C" fn bcmp(a: *const u8,
+                              b: *const u8,
+                              size: usize) -> i32 {
+    if size < 16 {
+        return if size >= 8 {
+            let head = (a as *const u64).read_unaligned() ^ (b as *const u64).read_unaligned();
+            let tail = (a.add(size - 8) as *const u64).read_unaligned()
+                ^ (b.add(size - 8) as *const u64).read_unaligned();
+            (head | tail != 0) as i32
+        } else if size >= 4 {
+            let head = (a as *const u32).read_unaligned() ^ (b as *const u32).read_unaligned();
+            let tail = (a.add(size - 4) as *const u32).read_unaligned()
+                ^ (b.add(size - 4) as *const u32).read_unaligned();
+            (head | tail != 0) as i32
+        } else {
+            (0..size).any(|at| *a.add(at) != *b.add(at)) as i32
+        };
+    }
+    let mut i = 0;
+    while i + 64 < size {
+        let mask = diff_mask(a.add(i), b.add(i))
+            | diff_mask(a.add(i + 16), b.add(i + 16))
+            | diff_mask(a.add(i + 32), b.add(i + 32))
+            | diff_mask(a.add(i + 48), b.add(i + 48));
+        if mask != 0 {
+            return 1;
+        }
+        i += 64;
+    }
+    while i + 16 < size {
+        if diff_mask(a.add(i), b.add(i)) != 0 {
+            return 1;
+        }
+        i += 16;
+    }
+    (diff_mask(a.add(size - 16), b.add(size - 16)) != 0) as i32
+}
 
+/// Scans aligned 16-byte blocks, which never straddle a page, so reading past
+/// the terminator cannot fault.
+#[cfg_attr(not(test), no_mangle)]
+pub unsafe extern "C" fn strlen(s: *const u8) -> usize {
+    use core::arch::x86_64::*;
+    let zero = _mm_setzero_si128();
+    let skew = s as usize & 15;
+    let mut block = s.sub(skew);
+    let mut mask = (_mm_movemask_epi8(_mm_cmpeq_epi8(_mm_load_si128(block as *const __m128i), zero)) as u32) >> skew << skew;
+    while mask == 0 {
+        block = block.add(16);
+        mask = _mm_movemask_epi8(_mm_cmpeq_epi8(_mm_load_si128(block as *const __m128i), zero)) as u32;
+    }
+    block as usize + mask.trailing_zeros() as usize - s as usize
+}
 
 #[cfg(not(test))]
 #[alloc_error_handler]
@@ -87,13 +373,26 @@ pub const MID_PAGE_SIZE : usize = 524288;
 pub const HUGE_PAGE_SHIFT : usize = 22;
 pub const HUGE_PAGE_SIZE : usize = 4194304;
 
+const WORD_SIZE : usize = size_of::<usize>();
+// page areas start on this boundary, so rounding a request up to an alignment
+// no larger than this yields aligned blocks in every size class
+const PAGE_ALIGN : usize = 64;
+const PAGES_PER_SEGMENT : usize = SEGMENT_SIZE / SMALL_PAGE_SIZE;
+const SMALL_OBJ_MAX : usize = SMALL_PAGE_SIZE / 8;
+const MID_OBJ_MAX : usize = MID_PAGE_SIZE / 8;
+const DIRECT_WSIZE_MAX : usize = 128;
+const BIN_COUNT : usize = bin_index(MID_OBJ_MAX) + 1;
+// carve fresh blocks out of a page a few at a time so untouched memory stays untouched
+const MAX_EXTEND_SIZE : usize = 4096;
+
+#[derive(Copy, Clone, PartialEq, Eq)]
 enum PageType {
     SMALL, MID, HUGE
 }
 
 #[repr(C)]
 struct LocalBlock {
-    next: *mut Block,
+    next: *mut LocalBlock,
 }
 
 #[repr(C)]
@@ -101,7 +400,8 @@ struct Block {
     next: AtomicPtr<Block>,
 }
 
-#[repr(C, align(4096))]
+// Page metadata lives in the segment header; the blocks live in the page area.
+#[repr(C)]
 struct Page {
     thread_free: AtomicPtr<Block>,
     local_free: *mut LocalBlock,
@@ -110,17 +410,52 @@ struct Page {
     next: *mut Page,
     prev: *mut Page,
     block_size: usize,
-    segment_idx: usize
+    segment_idx: usize,
+    // blocks handed out and not yet freed
+    used: usize,
+    // blocks carved into the free lists so far
+    capacity: usize,
+    // blocks that fit into the page area
+    reserved: usize,
+    in_use: bool,
+    in_full: bool,
+    // some block was handed out at an interior, over-aligned address
+    aligned: bool,
+    // unused but still committed, queued in the owner's purge queue
+    purge_pending: bool,
+    // milliseconds on the monotonic clock
+    purge_at: u64,
+    // bytes of the area handed back to the OS while unused
+    decommitted: usize,
+    // size class, zero for huge blocks
+    bin: usize,
 }
 
-#[repr(C, align(4194304))]
+#[repr(C)]
 struct Segment {
-    thread_id: u64,
+    // address of the owning heap, zero once abandoned
+    thread_id: AtomicU64,
     page_shift: usize,
-    page_start: *mut Page,
+    page_type: PageType,
+    page_count: usize,
+    // pages in use
+    used: usize,
+    // bytes mapped
+    size: usize,
+    numa_node: usize,
+    // cached with its pages still committed
+    dirty: bool,
+    // backed by pinned MAP_HUGETLB pages, which are never decommitted
+    hugetlb: bool,
+    // bytes handed back to the OS while cached
+    decommitted: usize,
+    purge_at: u64,
+    next: *mut Segment,
+    prev: *mut Segment,
+    pages: [Page; PAGES_PER_SEGMENT],
 }
 
-
+const SEGMENT_HEADER_SIZE : usize = (size_of::<Segment>() + PAGE_ALIGN - 1) & !(PAGE_ALIGN - 1);
 
 #[inline]
 fn address_hint(alignment: usize, size: usize) -> usize {
@@ -147,24 +482,79 @@ unsafe fn hinted_mmap(addr: *mut u8, size: usize, alignment: usize, prot_flags:
     let mut result = core::ptr::null_mut();
     let hint = address_hint(alignment, size);
     if addr.is_null() && hint != 0 {
+        MMAP_CALLS.fetch_add(1, Ordering::Relaxed);
         if let Ok(res) = syscall!(SYS_mmap, hint, size, prot_flags, map_flags, fd, 0) {
             result = res as *mut u8;
         }
     }
     if result.is_null() {
+        MMAP_CALLS.fetch_add(1, Ordering::Relaxed);
         if let Ok(res) = syscall!(SYS_mmap, addr, size, prot_flags, map_flags, fd, 0) {
             result = res as *mut u8;
         }
     }
+    if !result.is_null() {
+        MAPPED.fetch_add(size, Ordering::Relaxed);
+        recommit(size);
+    }
     result
 }
 
 unsafe fn munmap(addr: *mut u8, size: usize) -> bool {
-    syscall!(SYS_munmap, addr, size).is_ok()
+    MUNMAP_CALLS.fetch_add(1, Ordering::Relaxed);
+    let result = syscall!(SYS_munmap, addr, size).is_ok();
+    if result {
+        MAPPED.fetch_sub(size, Ordering::Relaxed);
+        COMMITTED.fetch_sub(size, Ordering::Relaxed);
+    }
+    result
+}
+
+static MAPPED : AtomicUsize = AtomicUsize::new(0);
+static COMMITTED : AtomicUsize = AtomicUsize::new(0);
+static PEAK_COMMITTED : AtomicUsize = AtomicUsize::new(0);
+static LIVE : AtomicIsize = AtomicIsize::new(0);
+static PEAK_LIVE : AtomicUsize = AtomicUsize::new(0);
+static SEGMENTS : AtomicUsize = AtomicUsize::new(0);
+static MMAP_CALLS : AtomicUsize = AtomicUsize::new(0);
+static MUNMAP_CALLS : AtomicUsize = AtomicUsize::new(0);
+static MREMAP_CALLS : AtomicUsize = AtomicUsize::new(0);
+static MADVISE_CALLS : AtomicUsize = AtomicUsize::new(0);
+
+/// Account for `size` bytes that may be backed by memory again.
+fn recommit(size: usize) {
+    let committed = COMMITTED.fetch_add(size, Ordering::Relaxed) + size;
+    PEAK_COMMITTED.fetch_max(committed, Ordering::Relaxed);
+}
+
+/// Live bytes a heap may count before passing them on to `LIVE`, so that
+/// most allocations do not write a shared cache line. The peak is exact to
+/// this much per thread.
+const LIVE_BATCH : isize = 64 << 10;
+
+/// Pass `delta` live bytes on to the process-wide count.
+fn flush_live(delta: isize) {
+    let live = LIVE.fetch_add(delta, Ordering::Relaxed) + delta;
+    if delta > 0 {
+        PEAK_LIVE.fetch_max(live.max(0) as usize, Ordering::Relaxed);
+    }
+}
+
+static NUMA_FAKE : AtomicUsize = AtomicUsize::new(0);
+
+/// Pretend the machine has `count` NUMA nodes, with threads assigned to nodes
+/// by CPU number. Allows exercising NUMA placement on single-node hosts;
+/// zero restores the detected topology.
+pub fn fake_numa_count(count: usize) {
+    NUMA_FAKE.store(count.min(NUMA_LIMIT), Ordering::Relaxed);
 }
 
 unsafe fn numa_count() -> usize {
     static mut NUMA_COUNT : usize = 0;
+    let fake = NUMA_FAKE.load(Ordering::Relaxed);
+    if fake != 0 {
+        return fake;
+    }
     static mut BUFFER : [u8;33] =  [0; 33];
     static PREFIX : &'static [u8] = b"/sys/devices/system/node/node";
     unsafe fn set(mut i: u8) {
@@ -212,29 +602,1691 @@ unsafe fn current_numa_node() -> usize {
     if numa_count() <= 1 {
         return 0;
     }
-    let mut node = 0;
-    let mut ncpu = 0;
-    if syscall!(SYS_getcpu, &ncpu as *const _, &node as *const _, 0).is_ok() {
-        node
+    let fake = NUMA_FAKE.load(Ordering::Relaxed);
+    match crate::vdso::getcpu() {
+        Ok((cpu, _)) if fake != 0 => cpu as usize % fake,
+        Ok((_, node)) => node as usize,
+        Err(_) => 0
+    }
+}
+
+#[inline]
+const fn wsize_of(size: usize) -> usize {
+    (size + WORD_SIZE - 1) / WORD_SIZE
+}
+
+/// Size class of a request: exact word sizes up to 8 words, then four classes
+/// per power of two.
+const fn bin_index(size: usize) -> usize {
+    let wsize = wsize_of(size);
+    if wsize <= 1 {
+        1
+    } else if wsize <= 8 {
+        wsize
     } else {
-        0
+        let w = wsize - 1;
+        let b = (63 - w.leading_zeros()) as usize;
+        (b << 2) + ((w >> (b - 2)) & 3) - 3
+    }
+}
+
+/// Largest request served by a size class.
+const fn bin_block_size(bin: usize) -> usize {
+    if bin <= 8 {
+        bin * WORD_SIZE
+    } else {
+        let b = (bin + 3) >> 2;
+        ((5 + ((bin + 3) & 3)) << (b - 2)) * WORD_SIZE
+    }
+}
+
+#[inline]
+fn align_up(size: usize, align: usize) -> usize {
+    (size + align - 1) & !(align - 1)
+}
+
+#[inline]
+fn segment_of<T>(ptr: *const T) -> *mut Segment {
+    // blocks never start at the segment base, but huge blocks aligned to a
+    // segment or more start exactly one segment after it
+    ((ptr as usize - 1) & SEGMENT_MASK) as *mut Segment
+}
+
+#[inline]
+unsafe fn page_of(segment: *mut Segment, ptr: *const u8) -> *mut Page {
+    if (*segment).page_type == PageType::HUGE {
+        return &mut (*segment).pages[0];
+    }
+    let idx = (ptr as usize - segment as usize) >> (*segment).page_shift;
+    &mut (*segment).pages[idx]
+}
+
+/// Bytes available for blocks in a small or mid page.
+#[inline]
+unsafe fn page_area_size(page: *const Page) -> usize {
+    let segment = segment_of(page);
+    (((*page).segment_idx + 1) << (*segment).page_shift) - (page_start(page) as usize - segment as usize)
+}
+
+/// Start of the block containing `ptr`, which may point into the middle of it
+/// when the block was handed out for an over-aligned request.
+#[inline]
+unsafe fn block_start(page: *const Page, ptr: *mut u8) -> *mut u8 {
+    if !(*page).aligned {
+        return ptr;
+    }
+    let start = page_start(page) as usize;
+    let size = (*page).block_size;
+    (start + (ptr as usize - start) / size * size) as *mut u8
+}
+
+/// Offset of the block in a huge segment and the alignment and phase of the
+/// mapping that puts the block on an `align` boundary.
+fn huge_placement(align: usize) -> (usize, usize, usize) {
+    if align < SEGMENT_SIZE {
+        (align_up(SEGMENT_HEADER_SIZE, align.max(PAGE_ALIGN)), SEGMENT_SIZE, 0)
+    } else {
+        (SEGMENT_SIZE, align, SEGMENT_SIZE)
     }
 }
 
+/// Start of the blocks of a page; the first page shares its area with the
+/// segment header.
+#[inline]
+unsafe fn page_start(page: *const Page) -> *mut u8 {
+    let segment = segment_of(page);
+    let offset = (*page).segment_idx << (*segment).page_shift;
+    (segment as usize + offset.max(SEGMENT_HEADER_SIZE)) as *mut u8
+}
+
+/// Map `size` bytes such that `offset` bytes in is aligned to `alignment`,
+/// trimming an over-sized mapping when the hint does not work out.
+unsafe fn aligned_mmap(size: usize, alignment: usize, offset: usize, extra_flags: u64) -> *mut u8 {
+    let prot = (flag::PROT_READ | flag::PROT_WRITE) as i64;
+    let map = (flag::MAP_PRIVATE | flag::MAP_ANON | extra_flags) as i64;
+    if offset % alignment == 0 {
+        let result = hinted_mmap(core::ptr::null_mut(), size, alignment, prot, map, -1);
+        if result.is_null() || result as usize % alignment == 0 {
+            return result;
+        }
+        munmap(result, size);
+    }
+    let result = hinted_mmap(core::ptr::null_mut(), size + alignment, 0, prot, map, -1);
+    if result.is_null() {
+        return result;
+    }
+    let aligned = (align_up(result as usize + offset, alignment) - offset) as *mut u8;
+    let aligned = if aligned < result { aligned.add(alignment) } else { aligned };
+    let head = aligned as usize - result as usize;
+    if head != 0 {
+        munmap(result, head);
+    }
+    if alignment - head != 0 {
+        munmap(aligned.add(size), alignment - head);
+    }
+    aligned
+}
+
 #[repr(C)]
-struct Heap {
-    next: *mut Block,
-    page_direct: [*mut Page; 128]
+struct PageQueue {
+    first: *mut Page,
+    last: *mut Page,
 }
 
-#[cfg(test)]
-mod test {
-    #[test]
-    fn test_numa() {
-        unsafe {
-            println!("{}, {}", super::numa_count(), super::numa_count());
+impl PageQueue {
+    unsafe fn push_front(&mut self, page: *mut Page) {
+        (*page).prev = core::ptr::null_mut();
+        (*page).next = self.first;
+        if self.first.is_null() {
+            self.last = page;
+        } else {
+            (*self.first).prev = page;
+        }
+        self.first = page;
+    }
+
+    unsafe fn remove(&mut self, page: *mut Page) {
+        if (*page).prev.is_null() {
+            self.first = (*page).next;
+        } else {
+            (*(*page).prev).next = (*page).next;
+        }
+        if (*page).next.is_null() {
+            self.last = (*page).prev;
+        } else {
+            (*(*page).next).prev = (*page).prev;
+        }
+        (*page).next = core::ptr::null_mut();
+        (*page).prev = core::ptr::null_mut();
+    }
+}
+
+#[repr(C)]
+struct SegmentQueue {
+    first: *mut Segment,
+}
+
+impl SegmentQueue {
+    unsafe fn push(&mut self, segment: *mut Segment) {
+        (*segment).prev = core::ptr::null_mut();
+        (*segment).next = self.first;
+        if !self.first.is_null() {
+            (*self.first).prev = segment;
+        }
+        self.first = segment;
+    }
+
+    unsafe fn remove(&mut self, segment: *mut Segment) {
+        if (*segment).prev.is_null() {
+            self.first = (*segment).next;
+        } else {
+            (*(*segment).prev).next = (*segment).next;
+        }
+        if !(*segment).next.is_null() {
+            (*(*segment).next).prev = (*segment).prev;
+        }
+        (*segment).next = core::ptr::null_mut();
+        (*segment).prev = core::ptr::null_mut();
+    }
+}
+
+/// Per-thread heap. An all-zero heap is a valid empty heap, so it can live
+/// inside a freshly cleared thread control block.
+#[repr(C)]
+pub(crate) struct Heap {
+    // first page with free blocks for every word size up to DIRECT_WSIZE_MAX
+    page_direct: [*mut Page; DIRECT_WSIZE_MAX],
+    pages: [PageQueue; BIN_COUNT],
+    // pages without any free block left
+    full: PageQueue,
+    // segments that still have unused pages
+    small_segments: SegmentQueue,
+    mid_segments: SegmentQueue,
+    // retired pages waiting to be decommitted, newest first
+    purge: PageQueue,
+    // bytes in the purge queue
+    retained: usize,
+    stats: HeapStats,
+    // links in the registry of running heaps
+    registered: bool,
+    next_heap: *mut Heap,
+    prev_heap: *mut Heap,
+}
+
+// Counters of one heap. Only the owner writes them, so a load and a store
+// suffice; `stats` reads them from other threads. Frees of other threads'
+// blocks are counted by the freeing heap, so single counters may go negative.
+#[repr(C)]
+struct HeapStats {
+    live: AtomicIsize,
+    /// Change of `live` not yet passed on to `LIVE`.
+    unflushed: AtomicIsize,
+    blocks: [AtomicIsize; BIN_COUNT],
+    allocated: [AtomicUsize; BIN_COUNT],
+}
+
+#[inline]
+fn bump(counter: &AtomicIsize, delta: isize) {
+    counter.store(counter.load(Ordering::Relaxed) + delta, Ordering::Relaxed);
+}
+
+impl HeapStats {
+    #[inline]
+    fn count_live(&self, delta: isize) {
+        bump(&self.live, delta);
+        let unflushed = self.unflushed.load(Ordering::Relaxed) + delta;
+        if unflushed > LIVE_BATCH || unflushed < -LIVE_BATCH {
+            flush_live(unflushed);
+            self.unflushed.store(0, Ordering::Relaxed);
+        } else {
+            self.unflushed.store(unflushed, Ordering::Relaxed);
+        }
+    }
+}
+
+impl Heap {
+    #[cfg(test)]
+    fn new() -> Self {
+        unsafe { core::mem::zeroed() }
+    }
+
+    #[inline]
+    fn id(&self) -> u64 {
+        self as *const Heap as u64
+    }
+
+    fn segments(&mut self, page_type: PageType) -> &mut SegmentQueue {
+        match page_type {
+            PageType::SMALL => &mut self.small_segments,
+            _ => &mut self.mid_segments,
+        }
+    }
+
+    /// Point the direct entries covered by `bin` at the head of its queue.
+    unsafe fn update_direct(&mut self, bin: usize) {
+        let upper = wsize_of(bin_block_size(bin));
+        if upper > DIRECT_WSIZE_MAX {
+            return;
+        }
+        let lower = if bin == 1 { 1 } else { wsize_of(bin_block_size(bin - 1)) + 1 };
+        for wsize in lower..=upper {
+            self.page_direct[wsize - 1] = self.pages[bin].first;
+        }
+    }
+
+    /// Count a block of `page` being allocated (`delta` = 1) or freed (-1).
+    #[inline]
+    unsafe fn count(&self, page: *const Page, delta: isize) {
+        let bin = (*page).bin;
+        self.stats.count_live(delta * (*page).block_size as isize);
+        bump(&self.stats.blocks[bin], delta);
+        if delta > 0 {
+            let allocated = &self.stats.allocated[bin];
+            allocated.store(allocated.load(Ordering::Relaxed) + 1, Ordering::Relaxed);
+        }
+    }
+
+    #[inline]
+    unsafe fn malloc(&mut self, size: usize) -> *mut u8 {
+        let idx = size.wrapping_sub(1) / WORD_SIZE;
+        if idx < DIRECT_WSIZE_MAX {
+            let page = self.page_direct[idx];
+            if !page.is_null() {
+                let block = (*page).free;
+                if !block.is_null() {
+                    (*page).free = (*block).next;
+                    (*page).used += 1;
+                    self.count(page, 1);
+                    return block as *mut u8;
+                }
+            }
+        }
+        self.malloc_generic(size)
+    }
+
+    unsafe fn malloc_generic(&mut self, size: usize) -> *mut u8 {
+        if size > MID_OBJ_MAX {
+            return self.malloc_huge(size, PAGE_ALIGN);
+        }
+        let bin = bin_index(size);
+        let mut page = self.find_page(bin);
+        if page.is_null() {
+            // recover blocks other threads gave back before asking for more memory
+            self.collect_full();
+            page = self.find_page(bin);
+        }
+        while page.is_null() && self.reclaim_abandoned() {
+            page = self.find_page(bin);
+        }
+        if page.is_null() {
+            page = self.page_alloc(bin);
+            if page.is_null() {
+                self.update_direct(bin);
+                return core::ptr::null_mut();
+            }
+            page_extend(page);
+        } else if self.pages[bin].first != page {
+            self.pages[bin].remove(page);
+            self.pages[bin].push_front(page);
+        }
+        self.update_direct(bin);
+        let block = (*page).free;
+        (*page).free = (*block).next;
+        (*page).used += 1;
+        self.count(page, 1);
+        block as *mut u8
+    }
+
+    /// First page of `bin` with a free block; pages found exhausted are moved
+    /// to the full queue on the way.
+    unsafe fn find_page(&mut self, bin: usize) -> *mut Page {
+        let mut page = self.pages[bin].first;
+        while !page.is_null() {
+            let next = (*page).next;
+            page_collect(page);
+            if (*page).free.is_null() {
+                page_extend(page);
+            }
+            if !(*page).free.is_null() {
+                return page;
+            }
+            self.pages[bin].remove(page);
+            self.full.push_front(page);
+            (*page).in_full = true;
+            page = next;
+        }
+        page
+    }
+
+    /// Bring full pages that received remote frees back into their queues.
+    unsafe fn collect_full(&mut self) {
+        let mut page = self.full.first;
+        while !page.is_null() {
+            let next = (*page).next;
+            if !(*page).thread_free.load(Ordering::Relaxed).is_null() {
+                page_collect(page);
+                self.page_unfull(page);
+                if (*page).used == 0 {
+                    self.page_retire(page, false);
+                }
+            }
+            page = next;
+        }
+    }
+
+    /// Adopt one segment left behind by an exited thread. Returns `false` if
+    /// there was none.
+    unsafe fn reclaim_abandoned(&mut self) -> bool {
+        let segment = {
+            let mut abandoned = ABANDONED.lock();
+            let segment = abandoned.first;
+            if segment.is_null() {
+                return false;
+            }
+            abandoned.remove(segment);
+            segment
+        };
+        (*segment).thread_id.store(self.id(), Ordering::Release);
+        let page_type = (*segment).page_type;
+        if (*segment).used < (*segment).page_count {
+            self.segments(page_type).push(segment);
+        }
+        for idx in 0..(*segment).page_count {
+            let page = &mut (*segment).pages[idx] as *mut Page;
+            if !(*page).in_use {
+                continue;
+            }
+            let bin = bin_index((*page).block_size);
+            self.pages[bin].push_front(page);
+            self.update_direct(bin);
+            page_collect(page);
+            if (*page).used == 0 {
+                let last = (*segment).used == 1;
+                self.page_retire(page, true);
+                if last {
+                    // the segment went back to the OS with its last page
+                    break;
+                }
+            }
+        }
+        true
+    }
+
+    /// Serve an alignment beyond what the size classes guarantee from a block
+    /// big enough to contain an aligned range of `size` bytes.
+    unsafe fn malloc_aligned(&mut self, size: usize, align: usize) -> *mut u8 {
+        let ptr = self.malloc(size + align - 1);
+        if ptr.is_null() || ptr as usize % align == 0 {
+            return ptr;
+        }
+        let segment = segment_of(ptr);
+        (*page_of(segment, ptr)).aligned = true;
+        align_up(ptr as usize, align) as *mut u8
+    }
+
+    unsafe fn malloc_huge(&mut self, size: usize, align: usize) -> *mut u8 {
+        let offset = huge_placement(align).0;
+        let segment = self.segment_alloc(PageType::HUGE, offset + size, align);
+        if segment.is_null() {
+            return core::ptr::null_mut();
+        }
+        let page = &mut (*segment).pages[0];
+        page.in_use = true;
+        page.block_size = size;
+        page.used = 1;
+        page.capacity = 1;
+        page.reserved = 1;
+        (*segment).used = 1;
+        self.count(page, 1);
+        (segment as *mut u8).add(offset)
+    }
+
+    unsafe fn segment_alloc(&mut self, page_type: PageType, required: usize, align: usize) -> *mut Segment {
+        let (size, page_shift) = match page_type {
+            PageType::SMALL => (SEGMENT_SIZE, SMALL_PAGE_SHIFT),
+            PageType::MID => (SEGMENT_SIZE, MID_PAGE_SHIFT),
+            // whole segments keep the address hints aligned; the tail is never touched
+            PageType::HUGE => (align_up(required, SEGMENT_SIZE), HUGE_PAGE_SHIFT),
+        };
+        let node = current_numa_node();
+        let mut segment = if page_type == PageType::HUGE {
+            core::ptr::null_mut()
+        } else {
+            cache_pop(node)
+        };
+        let hugetlb;
+        if segment.is_null() {
+            let (mapped, from_pool) = map_segment(page_type, size, align);
+            if mapped.is_null() {
+                return mapped;
+            }
+            segment = mapped;
+            hugetlb = from_pool;
+            SEGMENTS.fetch_add(1, Ordering::Relaxed);
+            bind_to_node(segment as *mut u8, size, node);
+        } else {
+            hugetlb = (*segment).hugetlb;
+            recommit((*segment).decommitted);
+            core::ptr::write_bytes(segment as *mut u8, 0, SEGMENT_HEADER_SIZE);
+        }
+        // the header is zeroed, so only the non-zero fields need filling in
+        (*segment).hugetlb = hugetlb;
+        (*segment).numa_node = node;
+        (*segment).thread_id.store(self.id(), Ordering::Release);
+        (*segment).page_shift = page_shift;
+        (*segment).page_type = page_type;
+        (*segment).page_count = if page_type == PageType::HUGE { 1 } else { SEGMENT_SIZE >> page_shift };
+        (*segment).size = size;
+        let header = &mut *segment;
+        for (idx, page) in header.pages[..header.page_count].iter_mut().enumerate() {
+            page.segment_idx = idx;
+            page.page_type = page_type;
+        }
+        segment
+    }
+
+    /// Take an unused page from one of our segments and set it up for `bin`.
+    unsafe fn page_alloc(&mut self, bin: usize) -> *mut Page {
+        let block_size = bin_block_size(bin);
+        let page_type = if block_size <= SMALL_OBJ_MAX { PageType::SMALL } else { PageType::MID };
+        let mut segment = self.segments(page_type).first;
+        if segment.is_null() {
+            segment = self.segment_alloc(page_type, 0, 0);
+            if segment.is_null() {
+                return core::ptr::null_mut();
+            }
+            self.segments(page_type).push(segment);
+        }
+        let header = &mut *segment;
+        let page = match header.pages[..header.page_count].iter_mut().find(|page| !page.in_use) {
+            Some(page) => page as *mut Page,
+            None => core::intrinsics::unreachable()
+        };
+        (*segment).used += 1;
+        if (*segment).used == (*segment).page_count {
+            self.segments(page_type).remove(segment);
+        }
+        self.purge_cancel(page);
+        recommit((*page).decommitted);
+        (*page).decommitted = 0;
+        (*page).in_use = true;
+        (*page).bin = bin;
+        (*page).block_size = block_size;
+        (*page).reserved = page_area_size(page) / block_size;
+        self.pages[bin].push_front(page);
+        page
+    }
+
+    unsafe fn free(&mut self, ptr: *mut u8) {
+        let segment = segment_of(ptr);
+        let page = page_of(segment, ptr);
+        self.count(page, -1);
+        if (*segment).page_type == PageType::HUGE {
+            segment_unmap(segment);
+            return;
+        }
+        let ptr = block_start(page, ptr);
+        if (*segment).thread_id.load(Ordering::Relaxed) != self.id() {
+            free_remote(page, ptr);
+            return;
+        }
+        let block = ptr as *mut LocalBlock;
+        (*block).next = (*page).local_free;
+        (*page).local_free = block;
+        (*page).used -= 1;
+        if (*page).used == 0 {
+            self.page_retire(page, false);
+        } else if (*page).in_full {
+            self.page_unfull(page);
+        }
+    }
+
+    unsafe fn abandon(&mut self, segment: *mut Segment) {
+        if (*segment).thread_id.load(Ordering::Relaxed) == 0 {
+            return;
+        }
+        if (*segment).used < (*segment).page_count {
+            self.segments((*segment).page_type).remove(segment);
+        }
+        (*segment).thread_id.store(0, Ordering::Release);
+        ABANDONED.lock().push(segment);
+    }
+
+    unsafe fn page_unfull(&mut self, page: *mut Page) {
+        let bin = bin_index((*page).block_size);
+        self.full.remove(page);
+        (*page).in_full = false;
+        self.pages[bin].push_front(page);
+        self.update_direct(bin);
+    }
+
+    /// Give an empty page back to its segment, and the segment back to the OS
+    /// once all of its pages are unused. The last page of a size class is kept
+    /// unless `force` is set, so that alloc/free cycles do not remap memory.
+    unsafe fn page_retire(&mut self, page: *mut Page, force: bool) {
+        let bin = bin_index((*page).block_size);
+        if (*page).in_full {
+            self.full.remove(page);
+            (*page).in_full = false;
+        } else {
+            if !force && self.pages[bin].first == page && (*page).next.is_null() {
+                return;
+            }
+            self.pages[bin].remove(page);
+        }
+        self.update_direct(bin);
+        let segment = segment_of(page);
+        let page_type = (*segment).page_type;
+        let idx = (*page).segment_idx;
+        core::ptr::write_bytes(page, 0, 1);
+        (*page).segment_idx = idx;
+        (*page).page_type = page_type;
+        if (*segment).used == (*segment).page_count {
+            self.segments(page_type).push(segment);
+        }
+        (*segment).used -= 1;
+        if (*segment).used == 0 {
+            self.segments(page_type).remove(segment);
+            for idx in 0..(*segment).page_count {
+                self.purge_cancel(&mut (*segment).pages[idx]);
+            }
+            segment_free(segment);
+        } else {
+            let now = now_ms();
+            (*page).purge_pending = true;
+            (*page).purge_at = now + PURGE_DELAY.load(Ordering::Relaxed);
+            self.purge.push_front(page);
+            self.retained += page_area_size(page);
+            self.purge(now, false);
+            cache_purge(now, false);
+        }
+    }
+
+    unsafe fn purge_cancel(&mut self, page: *mut Page) {
+        if (*page).purge_pending {
+            self.purge.remove(page);
+            self.retained -= page_area_size(page);
+            (*page).purge_pending = false;
+        }
+    }
+
+    /// Decommit retired pages whose delay has expired, and older ones beyond
+    /// the retain limit. `force` decommits all of them right away.
+    unsafe fn purge(&mut self, now: u64, force: bool) {
+        while !self.purge.last.is_null() {
+            let page = self.purge.last;
+            if !force && (*page).purge_at > now && self.retained <= RETAIN_LIMIT.load(Ordering::Relaxed) {
+                break;
+            }
+            self.purge_cancel(page);
+            if !(*segment_of(page)).hugetlb {
+                (*page).decommitted = decommit(page_start(page), page_area_size(page), force);
+            }
+        }
+    }
+}
+
+/// Milliseconds passed on the monotonic clock.
+fn now_ms() -> u64 {
+    match crate::vdso::clock_gettime(crate::time::CLOCK_MONOTONIC) {
+        Ok(ts) => ts.tv_sec as u64 * 1000 + ts.tv_nsec as u64 / 1_000_000,
+        Err(_) => 0
+    }
+}
+
+/// Give the OS pages in `[addr, addr + size)` back while keeping the range
+/// mapped. `MADV_FREE` is cheaper but only drops the pages under memory
+/// pressure, so `MADV_DONTNEED` is used when `now` is set or the kernel lacks
+/// the former.
+/// Returns the number of bytes decommitted.
+unsafe fn decommit(addr: *mut u8, size: usize, now: bool) -> usize {
+    static FREE_SUPPORTED : AtomicBool = AtomicBool::new(true);
+    let page_size = crate::auxv::page_size();
+    let start = align_up(addr as usize, page_size);
+    let end = (addr as usize + size) & !(page_size - 1);
+    if end <= start {
+        return 0;
+    }
+    COMMITTED.fetch_sub(end - start, Ordering::Relaxed);
+    if !now && FREE_SUPPORTED.load(Ordering::Relaxed) {
+        MADVISE_CALLS.fetch_add(1, Ordering::Relaxed);
+        if syscall!(SYS_madvise, start, end - start, flag::MADV_FREE).is_ok() {
+            return end - start;
         }
+        FREE_SUPPORTED.store(false, Ordering::Relaxed);
+    }
+    MADVISE_CALLS.fetch_add(1, Ordering::Relaxed);
+    match syscall!(SYS_madvise, start, end - start, flag::MADV_DONTNEED) {
+        _ => ()
     }
+    end - start
+}
+
+/// Resize the block of a huge segment without copying. Shrinking unmaps the
+/// tail; growing extends the mapping in place or moves it with `mremap` to a
+/// new address placed like the old one. Returns null if the block has to be
+/// copied.
+unsafe fn huge_resize(segment: *mut Segment, ptr: *mut u8, new_size: usize, align: usize) -> *mut u8 {
+    let offset = ptr as usize - segment as usize;
+    let size = (*segment).size;
+    let mut required = align_up(offset + new_size, crate::auxv::page_size());
+    let mut segment = segment;
+    if required < size {
+        munmap((segment as *mut u8).add(required), size - required);
+    } else if required > size {
+        required = align_up(required, SEGMENT_SIZE);
+        MREMAP_CALLS.fetch_add(1, Ordering::Relaxed);
+        if syscall!(SYS_mremap, segment, size, required, 0).is_ok() {
+            MAPPED.fetch_add(required - size, Ordering::Relaxed);
+            recommit(required - size);
+        } else {
+            // reserve an aligned range and move the pages over it
+            let (_, alignment, phase) = huge_placement(align);
+            let target = aligned_mmap(required, alignment, phase, 0);
+            if target.is_null() {
+                return target;
+            }
+            let flags = flag::MREMAP_MAYMOVE | flag::MREMAP_FIXED;
+            MREMAP_CALLS.fetch_add(1, Ordering::Relaxed);
+            if syscall!(SYS_mremap, segment, size, required, flags, target).is_err() {
+                munmap(target, required);
+                return core::ptr::null_mut();
+            }
+            // the reservation was accounted for, the old range is gone
+            MAPPED.fetch_sub(size, Ordering::Relaxed);
+            COMMITTED.fetch_sub(size, Ordering::Relaxed);
+            segment = target as *mut Segment;
+        }
+    }
+    (*segment).size = required;
+    (*segment).pages[0].block_size = new_size;
+    (segment as *mut u8).add(offset)
+}
+
+/// Hand a block back to a page owned by another thread (or by nobody).
+///
+/// Pushers never pop, and the owner takes the whole list at once, so a plain
+/// CAS push is free of ABA.
+unsafe fn free_remote(page: *mut Page, ptr: *mut u8) {
+    let block = ptr as *mut Block;
+    let mut head = (*page).thread_free.load(Ordering::Relaxed);
+    loop {
+        (*block).next.store(head, Ordering::Relaxed);
+        match (*page).thread_free.compare_exchange_weak(head, block, Ordering::Release, Ordering::Relaxed) {
+            Ok(_) => break,
+            Err(current) => head = current
+        }
+    }
+}
+
+/// Move blocks freed by the owner, and in bulk those freed by other threads,
+/// back to the allocation list.
+#[inline]
+unsafe fn page_collect(page: *mut Page) {
+    if !(*page).thread_free.load(Ordering::Relaxed).is_null() {
+        let head = (*page).thread_free.swap(core::ptr::null_mut(), Ordering::Acquire);
+        let mut tail = head;
+        let mut count = 1;
+        loop {
+            let next = (*tail).next.load(Ordering::Relaxed);
+            if next.is_null() {
+                break;
+            }
+            tail = next;
+            count += 1;
+        }
+        // both block kinds are a single next pointer
+        (*(tail as *mut LocalBlock)).next = (*page).local_free;
+        (*page).local_free = head as *mut LocalBlock;
+        (*page).used -= count;
+    }
+    if (*page).free.is_null() {
+        (*page).free = (*page).local_free;
+        (*page).local_free = core::ptr::null_mut();
+    }
+}
+
+/// Carve the next run of never-used blocks into the (empty) free list.
+unsafe fn page_extend(page: *mut Page) {
+    let page = &mut *page;
+    let remaining = page.reserved - page.capacity;
+    if remaining == 0 {
+        return;
+    }
+    let size = page.block_size;
+    let count = remaining.min(MAX_EXTEND_SIZE / size).max(1);
+    let first = page_start(page).add(page.capacity * size);
+    for i in 0..count - 1 {
+        (*(first.add(i * size) as *mut LocalBlock)).next = first.add((i + 1) * size) as *mut LocalBlock;
+    }
+    (*(first.add((count - 1) * size) as *mut LocalBlock)).next = page.free;
+    page.free = first as *mut LocalBlock;
+    page.capacity += count;
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum HugePages {
+    /// Normal pages only.
+    Off,
+    /// Mark segments with `MADV_HUGEPAGE` so transparent huge pages back them.
+    Transparent,
+    /// Map segments with `MAP_HUGETLB` from the reserved pool, falling back to
+    /// normal pages when it is exhausted. Segments of huge blocks, which are
+    /// resized at page granularity, always use normal pages.
+    Hugetlb,
+}
+
+static HUGE_PAGES : AtomicUsize = AtomicUsize::new(HugePages::Off as usize);
+static TRANSPARENT_OK : AtomicUsize = AtomicUsize::new(0);
+static TRANSPARENT_FAILED : AtomicUsize = AtomicUsize::new(0);
+static HUGETLB_OK : AtomicUsize = AtomicUsize::new(0);
+static HUGETLB_FAILED : AtomicUsize = AtomicUsize::new(0);
+
+/// Choose how new segments are backed.
+pub fn set_huge_pages(mode: HugePages) {
+    HUGE_PAGES.store(mode as usize, Ordering::Relaxed);
+}
+
+/// How often each huge page path succeeded or failed since start-up.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct HugePageStats {
+    pub transparent: usize,
+    pub transparent_failed: usize,
+    pub hugetlb: usize,
+    pub hugetlb_failed: usize,
+}
+
+pub fn huge_page_stats() -> HugePageStats {
+    HugePageStats {
+        transparent: TRANSPARENT_OK.load(Ordering::Relaxed),
+        transparent_failed: TRANSPARENT_FAILED.load(Ordering::Relaxed),
+        hugetlb: HUGETLB_OK.load(Ordering::Relaxed),
+        hugetlb_failed: HUGETLB_FAILED.load(Ordering::Relaxed),
+    }
+}
+
+/// Map a new segment according to the huge page mode. The flag tells whether
+/// the mapping came from the hugetlb pool.
+unsafe fn map_segment(page_type: PageType, size: usize, align: usize) -> (*mut Segment, bool) {
+    let (_, alignment, offset) = huge_placement(align);
+    let mode = HUGE_PAGES.load(Ordering::Relaxed);
+    if mode == HugePages::Hugetlb as usize && page_type != PageType::HUGE {
+        let segment = aligned_mmap(size, alignment, offset, flag::MAP_HUGETLB);
+        if !segment.is_null() {
+            HUGETLB_OK.fetch_add(1, Ordering::Relaxed);
+            return (segment as *mut Segment, true);
+        }
+        HUGETLB_FAILED.fetch_add(1, Ordering::Relaxed);
+    }
+    let segment = aligned_mmap(size, alignment, offset, 0);
+    if !segment.is_null() && mode == HugePages::Transparent as usize {
+        if syscall!(SYS_madvise, segment, size, flag::MADV_HUGEPAGE).is_ok() {
+            TRANSPARENT_OK.fetch_add(1, Ordering::Relaxed);
+        } else {
+            TRANSPARENT_FAILED.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+    (segment as *mut Segment, false)
+}
+
+/// Ask the kernel to back a fresh mapping with memory from `node`. Failing is
+/// harmless: the memory then follows the default policy.
+unsafe fn bind_to_node(addr: *mut u8, size: usize, node: usize) {
+    if numa_count() <= 1 || node >= NUMA_LIMIT {
+        return;
+    }
+    let mut mask = [0u64; NUMA_LIMIT / 64];
+    mask[node / 64] |= 1 << (node % 64);
+    match syscall!(SYS_mbind, addr, size, flag::MPOL_PREFERRED, mask.as_ptr(), NUMA_LIMIT, 0) {
+        _ => ()
+    }
+}
+
+const SEGMENT_CACHE_LIMIT : usize = 4;
+
+#[derive(Copy, Clone)]
+struct NodeCache {
+    first: *mut Segment,
+    count: usize,
+}
+
+// empty segments kept around per node, linked through `Segment::next`
+static SEGMENT_CACHE: Futex<[NodeCache; NUMA_LIMIT]> = Futex {
+    _flag: AtomicU64::new(0),
+    _elision: AtomicU32::new(0),
+    item: UnsafeCell::new([NodeCache {
+        first: core::ptr::null_mut(),
+        count: 0,
+    }; NUMA_LIMIT])
+};
+
+unsafe fn cache_pop(node: usize) -> *mut Segment {
+    let mut caches = SEGMENT_CACHE.lock();
+    let cache = &mut caches[node % NUMA_LIMIT];
+    let segment = cache.first;
+    if !segment.is_null() {
+        cache.first = (*segment).next;
+        cache.count -= 1;
+    }
+    segment
+}
+
+/// Return an empty segment to the cache of its node, or to the OS if that is full.
+unsafe fn segment_free(segment: *mut Segment) {
+    for idx in 0..(*segment).page_count {
+        (*segment).decommitted += (*segment).pages[idx].decommitted;
+    }
+    if (*segment).page_type != PageType::HUGE {
+        let mut caches = SEGMENT_CACHE.lock();
+        let cache = &mut caches[(*segment).numa_node % NUMA_LIMIT];
+        if cache.count < SEGMENT_CACHE_LIMIT {
+            (*segment).dirty = true;
+            (*segment).purge_at = now_ms() + PURGE_DELAY.load(Ordering::Relaxed);
+            (*segment).next = cache.first;
+            cache.first = segment;
+            cache.count += 1;
+            return;
+        }
+    }
+    segment_unmap(segment);
+}
+
+unsafe fn segment_unmap(segment: *mut Segment) {
+    // munmap accounts the whole mapping as no longer committed
+    recommit((*segment).decommitted);
+    SEGMENTS.fetch_sub(1, Ordering::Relaxed);
+    munmap(segment as *mut u8, (*segment).size);
+}
+
+/// Decommit cached segments idle for longer than the purge delay. `force`
+/// unmaps every cached segment instead.
+unsafe fn cache_purge(now: u64, force: bool) {
+    static NEXT_PURGE : AtomicU64 = AtomicU64::new(0);
+    if !force && now < NEXT_PURGE.load(Ordering::Relaxed) {
+        return;
+    }
+    NEXT_PURGE.store(now + PURGE_DELAY.load(Ordering::Relaxed).max(1), Ordering::Relaxed);
+    let mut caches = SEGMENT_CACHE.lock();
+    for cache in caches.iter_mut() {
+        if force {
+            while !cache.first.is_null() {
+                let segment = cache.first;
+                cache.first = (*segment).next;
+                segment_unmap(segment);
+            }
+            cache.count = 0;
+            continue;
+        }
+        let mut segment = cache.first;
+        while !segment.is_null() {
+            if (*segment).dirty && !(*segment).hugetlb && (*segment).purge_at <= now {
+                let header = align_up(SEGMENT_HEADER_SIZE, crate::auxv::page_size());
+                // the range covers whatever its pages had decommitted before
+                COMMITTED.fetch_add((*segment).decommitted, Ordering::Relaxed);
+                (*segment).decommitted = decommit((segment as *mut u8).add(header), (*segment).size - header, false);
+                (*segment).dirty = false;
+            }
+            segment = (*segment).next;
+        }
+    }
+}
+
+static PURGE_DELAY : AtomicU64 = AtomicU64::new(10);
+static RETAIN_LIMIT : AtomicUsize = AtomicUsize::new(16 << 20);
+
+/// How long freed pages and cached segments stay committed before they are
+/// given back to the OS.
+pub fn set_purge_delay(delay: core::time::Duration) {
+    PURGE_DELAY.store(delay.as_millis() as u64, Ordering::Relaxed);
+}
+
+/// How many bytes of freed pages a thread may keep committed regardless of
+/// the purge delay.
+pub fn set_retain_limit(bytes: usize) {
+    RETAIN_LIMIT.store(bytes, Ordering::Relaxed);
+}
+
+/// Return the calling thread's unused memory to the OS. Without `force` only
+/// memory idle for longer than the purge delay is decommitted; with it all
+/// empty pages are released and cached segments are unmapped.
+pub fn collect(force: bool) {
+    unsafe {
+        let heap = local_heap();
+        heap.collect_full();
+        for bin in 1..BIN_COUNT {
+            let mut page = heap.pages[bin].first;
+            while !page.is_null() {
+                let next = (*page).next;
+                page_collect(page);
+                if (*page).used == 0 {
+                    heap.page_retire(page, force);
+                }
+                page = next;
+            }
+        }
+        let now = now_ms();
+        heap.purge(now, force);
+        cache_purge(now, force);
+    }
+}
+
+struct Registry {
+    first: *mut Heap,
+    // counters of heaps whose threads have exited
+    live: isize,
+    blocks: [isize; BIN_COUNT],
+    allocated: [usize; BIN_COUNT],
+}
+
+static REGISTRY: Futex<Registry> = Futex {
+    _flag: AtomicU64::new(0),
+    _elision: AtomicU32::new(0),
+    item: UnsafeCell::new(Registry {
+        first: core::ptr::null_mut(),
+        live: 0,
+        blocks: [0; BIN_COUNT],
+        allocated: [0; BIN_COUNT],
+    })
+};
+
+/// Make a thread's heap visible to `stats`. The heap must not move afterwards.
+pub(crate) unsafe fn heap_init(heap: &mut Heap) {
+    let mut registry = REGISTRY.lock();
+    heap.next_heap = registry.first;
+    heap.prev_heap = core::ptr::null_mut();
+    if !registry.first.is_null() {
+        (*registry.first).prev_heap = heap;
+    }
+    registry.first = heap;
+    heap.registered = true;
+}
+
+/// Fold the counters of a finished heap into the totals and forget it.
+unsafe fn heap_unregister(heap: &mut Heap) {
+    let mut registry = REGISTRY.lock();
+    registry.live += heap.stats.live.load(Ordering::Relaxed);
+    flush_live(heap.stats.unflushed.swap(0, Ordering::Relaxed));
+    for bin in 0..BIN_COUNT {
+        registry.blocks[bin] += heap.stats.blocks[bin].load(Ordering::Relaxed);
+        registry.allocated[bin] += heap.stats.allocated[bin].load(Ordering::Relaxed);
+    }
+    if !heap.registered {
+        return;
+    }
+    if heap.prev_heap.is_null() {
+        registry.first = heap.next_heap;
+    } else {
+        (*heap.prev_heap).next_heap = heap.next_heap;
+    }
+    if !heap.next_heap.is_null() {
+        (*heap.next_heap).prev_heap = heap.prev_heap;
+    }
+    heap.registered = false;
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+pub struct BinStats {
+    /// Largest request served, zero for huge blocks.
+    pub block_size: usize,
+    /// Blocks currently allocated.
+    pub live: usize,
+    /// Blocks allocated since start-up.
+    pub allocated: usize,
+}
+
+#[derive(Copy, Clone)]
+pub struct Stats {
+    /// Bytes in allocated blocks, rounded up to their size class.
+    pub live: usize,
+    /// Bytes of address space mapped by the allocator.
+    pub mapped: usize,
+    /// Mapped bytes not handed back to the OS through `madvise`.
+    pub committed: usize,
+    /// Highest value `committed` has reached.
+    pub peak_committed: usize,
+    /// Highest value `live` has reached.
+    pub peak_live: usize,
+    pub segments: usize,
+    pub mmap_calls: usize,
+    pub munmap_calls: usize,
+    pub mremap_calls: usize,
+    pub madvise_calls: usize,
+    /// Indexed by size class; the first entry counts huge blocks.
+    pub bins: [BinStats; BIN_COUNT],
+}
+
+/// Snapshot of the allocator counters of the whole process. Counters of
+/// running threads are read without stopping them, so the figures are only
+/// consistent with each other approximately.
+pub fn stats() -> Stats {
+    let mut result = Stats {
+        live: 0,
+        mapped: MAPPED.load(Ordering::Relaxed),
+        committed: COMMITTED.load(Ordering::Relaxed),
+        peak_committed: PEAK_COMMITTED.load(Ordering::Relaxed),
+        peak_live: PEAK_LIVE.load(Ordering::Relaxed),
+        segments: SEGMENTS.load(Ordering::Relaxed),
+        mmap_calls: MMAP_CALLS.load(Ordering::Relaxed),
+        munmap_calls: MUNMAP_CALLS.load(Ordering::Relaxed),
+        mremap_calls: MREMAP_CALLS.load(Ordering::Relaxed),
+        madvise_calls: MADVISE_CALLS.load(Ordering::Relaxed),
+        bins: [BinStats::default(); BIN_COUNT],
+    };
+    let registry = REGISTRY.lock();
+    let mut live = registry.live;
+    let mut blocks = registry.blocks;
+    let mut allocated = registry.allocated;
+    let mut heap = registry.first;
+    while !heap.is_null() {
+        unsafe {
+            let stats = &(*heap).stats;
+            live += stats.live.load(Ordering::Relaxed);
+            for bin in 0..BIN_COUNT {
+                blocks[bin] += stats.blocks[bin].load(Ordering::Relaxed);
+                allocated[bin] += stats.allocated[bin].load(Ordering::Relaxed);
+            }
+            heap = (*heap).next_heap;
+        }
+    }
+    result.live = live.max(0) as usize;
+    result.peak_live = result.peak_live.max(result.live);
+    for bin in 0..BIN_COUNT {
+        result.bins[bin] = BinStats {
+            block_size: if bin == 0 { 0 } else { bin_block_size(bin) },
+            live: blocks[bin].max(0) as usize,
+            allocated: allocated[bin],
+        };
+    }
+    result
+}
+
+/// Write `stats` to standard error.
+pub fn print_stats() {
+    use core::fmt::Write;
+    let stats = stats();
+    let huge = huge_page_stats();
+    let mut out = EWRITER.lock();
+    let _ = writeln!(out, "heap: live {} peak {} mapped {} committed {} peak committed {} segments {}",
+                     stats.live, stats.peak_live, stats.mapped, stats.committed, stats.peak_committed,
+                     stats.segments);
+    let _ = writeln!(out, "os: mmap {} munmap {} mremap {} madvise {}",
+                     stats.mmap_calls, stats.munmap_calls, stats.mremap_calls, stats.madvise_calls);
+    let _ = writeln!(out, "huge pages: transparent {}/{} hugetlb {}/{}",
+                     huge.transparent, huge.transparent + huge.transparent_failed,
+                     huge.hugetlb, huge.hugetlb + huge.hugetlb_failed);
+    for bin in stats.bins.iter().filter(|bin| bin.allocated != 0) {
+        if bin.block_size == 0 {
+            let _ = writeln!(out, "  huge: live {} allocated {}", bin.live, bin.allocated);
+        } else {
+            let _ = writeln!(out, "  {:>6}: live {} allocated {}", bin.block_size, bin.live, bin.allocated);
+        }
+    }
+}
+
+// segments whose owner exited while some of their blocks were still live
+static ABANDONED: Futex<SegmentQueue> = Futex {
+    _flag: AtomicU64::new(0),
+    _elision: AtomicU32::new(0),
+    item: UnsafeCell::new(SegmentQueue {
+        first: core::ptr::null_mut(),
+    })
+};
+
+/// Tear down the heap of an exiting thread: empty pages are released, and
+/// segments still holding live blocks are abandoned for other heaps to adopt.
+/// Blocks freed into them meanwhile pile up on their `thread_free` lists.
+pub(crate) unsafe fn heap_done(heap: &mut Heap) {
+    heap.collect_full();
+    for bin in 1..BIN_COUNT {
+        let mut page = heap.pages[bin].first;
+        while !page.is_null() {
+            let next = (*page).next;
+            page_collect(page);
+            if (*page).used == 0 {
+                heap.page_retire(page, true);
+            }
+            page = next;
+        }
+    }
+    // nobody would drain the queue of a dead heap
+    heap.purge(now_ms(), true);
+    for bin in 1..BIN_COUNT {
+        while !heap.pages[bin].first.is_null() {
+            let page = heap.pages[bin].first;
+            heap.pages[bin].remove(page);
+            heap.abandon(segment_of(page));
+        }
+        heap.update_direct(bin);
+    }
+    while !heap.full.first.is_null() {
+        let page = heap.full.first;
+        heap.full.remove(page);
+        (*page).in_full = false;
+        heap.abandon(segment_of(page));
+    }
+    heap_unregister(heap);
+}
+
+#[cfg(not(test))]
+#[inline(always)]
+unsafe fn local_heap() -> &'static mut Heap {
+    &mut crate::thread::thread_self().heap
+}
+
+#[cfg(test)]
+struct LocalHeap(UnsafeCell<Heap>);
+
+#[cfg(test)]
+impl Drop for LocalHeap {
+    fn drop(&mut self) {
+        unsafe { heap_done(&mut *self.0.get()) }
+    }
+}
+
+#[cfg(test)]
+unsafe fn local_heap() -> &'static mut Heap {
+    thread_local! {
+        static HEAP: LocalHeap = LocalHeap(UnsafeCell::new(Heap::new()));
+    }
+    HEAP.with(|heap| {
+        let heap = &mut *heap.0.get();
+        if !heap.registered {
+            heap_init(heap);
+        }
+        heap
+    })
+}
+
+/// Size-classed segment allocator with one heap per thread.
+pub struct SegmentAllocator;
+
+#[cfg_attr(not(any(test, feature = "debug-alloc", feature = "leak-report")), global_allocator)]
+pub static SEGMENT_ALLOC: SegmentAllocator = SegmentAllocator;
+
+unsafe impl core::alloc::GlobalAlloc for SegmentAllocator {
+    #[inline]
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let heap = local_heap();
+        let align = layout.align();
+        if align <= WORD_SIZE {
+            heap.malloc(layout.size())
+        } else if align <= PAGE_ALIGN && layout.size() <= MID_OBJ_MAX {
+            heap.malloc(align_up(layout.size().max(align), align))
+        } else if layout.size() + align <= MID_OBJ_MAX {
+            heap.malloc_aligned(layout.size(), align)
+        } else {
+            heap.malloc_huge(layout.size(), align)
+        }
+    }
+
+    #[inline]
+    unsafe fn dealloc(&self, ptr: *mut u8, _layout: Layout) {
+        local_heap().free(ptr)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let segment = segment_of(ptr);
+        let page = page_of(segment, ptr);
+        if (*segment).page_type == PageType::HUGE {
+            let new_ptr = huge_resize(segment, ptr, new_size, layout.align());
+            if !new_ptr.is_null() {
+                local_heap().stats.count_live(new_size as isize - layout.size() as isize);
+                return new_ptr;
+            }
+        } else if new_size <= (*page).block_size - (ptr as usize - block_start(page, ptr) as usize)
+            && new_size >= (*page).block_size / 2 {
+            // still fits and does not waste more than half of the block
+            return ptr;
+        }
+        let new_ptr = self.alloc(Layout::from_size_align_unchecked(new_size, layout.align()));
+        if !new_ptr.is_null() {
+            core::ptr::copy_nonoverlapping(ptr, new_ptr, layout.size().min(new_size));
+            self.dealloc(ptr, layout);
+        }
+        new_ptr
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use core::alloc::GlobalAlloc;
+
+    // sizes around every path switch, at every misalignment of both ends
+    fn copy_sizes() -> impl Iterator<Item = usize> {
+        (0..=300).chain([REP_THRESHOLD - 1, REP_THRESHOLD, REP_THRESHOLD + 77,
+                         NON_TEMPORAL_THRESHOLD - 1, NON_TEMPORAL_THRESHOLD,
+                         NON_TEMPORAL_THRESHOLD + 93].iter().cloned())
+    }
+
+    #[test]
+    fn test_memcpy() {
+        let src: std::vec::Vec<u8> = (0..NON_TEMPORAL_THRESHOLD + 128).map(|i| (i * 7 + i / 251) as u8).collect();
+        let mut dst = std::vec![0u8; NON_TEMPORAL_THRESHOLD + 128];
+        for size in copy_sizes() {
+            for (src_offset, dst_offset) in [(0, 0), (1, 0), (0, 3), (5, 11), (16, 7), (15, 15)].iter().cloned() {
+                let end = (dst_offset + size + 64).min(dst.len());
+                for byte in dst[..end].iter_mut() {
+                    *byte = 0xEE;
+                }
+                let ret = unsafe {
+                    memcpy(dst.as_mut_ptr().add(dst_offset), src.as_ptr().add(src_offset), size)
+                };
+                assert_eq!(ret, unsafe { dst.as_mut_ptr().add(dst_offset) });
+                assert!(dst[..dst_offset].iter().all(|&byte| byte == 0xEE));
+                assert!(dst[dst_offset..dst_offset + size] == src[src_offset..src_offset + size],
+                        "size {} offsets {} {}", size, src_offset, dst_offset);
+                assert!(dst[dst_offset + size..end].iter().all(|&byte| byte == 0xEE));
+            }
+        }
+    }
+
+    #[test]
+    fn test_memset() {
+        let mut dst = std::vec![0u8; NON_TEMPORAL_THRESHOLD + 128];
+        for size in copy_sizes() {
+            for offset in [0, 1, 7, 8, 15].iter().cloned() {
+                let end = (offset + size + 64).min(dst.len());
+                for byte in dst[..end].iter_mut() {
+                    *byte = 0xEE;
+                }
+                // only the low byte of the value counts
+                let ret = unsafe { memset(dst.as_mut_ptr().add(offset), 0x1A5, size) };
+                assert_eq!(ret, unsafe { dst.as_mut_ptr().add(offset) });
+                assert!(dst[..offset].iter().all(|&byte| byte == 0xEE));
+                assert!(dst[offset..offset + size].iter().all(|&byte| byte == 0xA5), "size {} offset {}", size, offset);
+                assert!(dst[offset + size..end].iter().all(|&byte| byte == 0xEE));
+            }
+        }
+    }
+
+    #[test]
+    fn test_memmove() {
+        let pattern: std::vec::Vec<u8> = (0..8192).map(|i| (i * 13 + i / 256) as u8).collect();
+        for size in (0..=300).chain([1000, 4096, 5000].iter().cloned()) {
+            for &shift in [1, 3, 15, 16, 17, 31, 32, 33, 64, 100, 400, 3000].iter() {
+                for &(src, dst) in [(0, shift), (shift, 0)].iter() {
+                    let mut buffer = pattern.clone();
+                    let mut expected = pattern.clone();
+                    expected.copy_within(src..src + size, dst);
+                    let ret = unsafe {
+                        memmove(buffer.as_mut_ptr().add(dst), buffer.as_ptr().add(src), size)
+                    };
+                    assert_eq!(ret, unsafe { buffer.as_mut_ptr().add(dst) });
+                    assert!(buffer == expected, "size {} from {} to {}", size, src, dst);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_memcmp() {
+        let base: std::vec::Vec<u8> = (0..400).map(|i| (i * 31) as u8).collect();
+        for size in 0..=300 {
+            for &offset in [0, 1, 9].iter() {
+                let a = &base[offset..offset + size];
+                let mut b = a.to_vec();
+                unsafe {
+                    assert_eq!(memcmp(a.as_ptr(), b.as_ptr(), size), 0);
+                    assert_eq!(bcmp(a.as_ptr(), b.as_ptr(), size), 0);
+                }
+                for at in 0..size {
+                    for &delta in [1u8, 0x80, 0xFF].iter() {
+                        b[at] = a[at].wrapping_add(delta);
+                        let result = unsafe { memcmp(a.as_ptr(), b.as_ptr(), size) };
+                        assert_eq!(result.signum(), a.cmp(&b[..]) as i32, "size {} at {}", size, at);
+                        assert_ne!(unsafe { bcmp(a.as_ptr(), b.as_ptr(), size) }, 0);
+                        b[at] = a[at];
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_strlen() {
+        unsafe {
+            // strings ending right before an inaccessible page
+            let map = syscall!(SYS_mmap, 0, 8192, flag::PROT_READ | flag::PROT_WRITE,
+                               flag::MAP_PRIVATE | flag::MAP_ANON, -1i64, 0).unwrap() as *mut u8;
+            syscall!(SYS_mprotect, map.add(4096), 4096, flag::PROT_NONE).unwrap();
+            for len in 0..200 {
+                for skew in 0..16 {
+                    let s = map.add(4096 - len - 1 - skew);
+                    memset(map, b'x' as i32, 4096);
+                    *s.add(len) = 0;
+                    assert_eq!(strlen(s), len);
+                }
+            }
+            syscall!(SYS_munmap, map, 8192).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_bins() {
+        for size in 0..=MID_OBJ_MAX {
+            let bin = bin_index(size);
+            assert!(bin < BIN_COUNT);
+            assert!(bin_block_size(bin) >= size);
+            assert!(bin == 1 || bin_block_size(bin - 1) < size);
+        }
+        for bin in 1..BIN_COUNT {
+            assert_eq!(bin_index(bin_block_size(bin)), bin);
+        }
+        assert!(SEGMENT_HEADER_SIZE < SMALL_PAGE_SIZE);
+    }
+
+    #[test]
+    fn test_alloc_free() {
+        unsafe {
+            let mut blocks = std::vec::Vec::new();
+            for &align in &[1, 8, 16, 32, 64, 4096] {
+                for size in (0..MID_OBJ_MAX * 2).step_by(997).chain(1..=DIRECT_WSIZE_MAX * WORD_SIZE) {
+                    let layout = Layout::from_size_align(size, align).unwrap();
+                    let ptr = SEGMENT_ALLOC.alloc(layout);
+                    assert!(!ptr.is_null());
+                    assert_eq!(ptr as usize % align, 0);
+                    core::ptr::write_bytes(ptr, size as u8, size);
+                    blocks.push((ptr, layout));
+                }
+            }
+            for &(ptr, layout) in &blocks {
+                for i in 0..layout.size() {
+                    assert_eq!(*ptr.add(i), layout.size() as u8);
+                }
+                SEGMENT_ALLOC.dealloc(ptr, layout);
+            }
+        }
+    }
+
+    #[test]
+    fn test_reuse() {
+        unsafe {
+            // freed blocks are recycled instead of growing the heap
+            let layout = Layout::from_size_align(24, 8).unwrap();
+            let first = SEGMENT_ALLOC.alloc(layout);
+            let page = page_of(segment_of(first), first);
+            SEGMENT_ALLOC.dealloc(first, layout);
+            for _ in 0..100000 {
+                let ptr = SEGMENT_ALLOC.alloc(layout);
+                assert_eq!(page_of(segment_of(ptr), ptr), page);
+                SEGMENT_ALLOC.dealloc(ptr, layout);
+            }
+        }
+    }
+
+    #[test]
+    fn test_remote_free() {
+        // the consumer frees everything the producer allocates
+        let (sender, receiver) = std::sync::mpsc::sync_channel::<(usize, Layout)>(1024);
+        let consumer = std::thread::spawn(move || unsafe {
+            let mut count = 0;
+            for (ptr, layout) in receiver {
+                assert_eq!(*(ptr as *const usize), ptr);
+                SEGMENT_ALLOC.dealloc(ptr as *mut u8, layout);
+                count += 1;
+            }
+            count
+        });
+        let mut pages = std::collections::HashSet::new();
+        unsafe {
+            for i in 0..200000usize {
+                let layout = Layout::from_size_align(i % 7 * 8 + 8, 8).unwrap();
+                let ptr = SEGMENT_ALLOC.alloc(layout) as usize;
+                *(ptr as *mut usize) = ptr;
+                pages.insert(ptr & !(SMALL_PAGE_SIZE - 1));
+                sender.send((ptr, layout)).unwrap();
+            }
+        }
+        drop(sender);
+        assert_eq!(consumer.join().unwrap(), 200000);
+        // remote frees were recycled rather than piling up in fresh pages
+        assert!(pages.len() < 64, "{} pages", pages.len());
+    }
+
+    #[test]
+    fn test_abandoned() {
+        let layout = Layout::from_size_align(48, 8).unwrap();
+        let blocks = std::thread::spawn(move || unsafe {
+            (0..1000).map(|_| SEGMENT_ALLOC.alloc(layout) as usize).collect::<std::vec::Vec<_>>()
+        }).join().unwrap();
+        unsafe {
+            let segment = segment_of(blocks[0] as *const u8);
+            assert_eq!((*segment).thread_id.load(Ordering::Acquire), 0);
+            for &ptr in &blocks {
+                SEGMENT_ALLOC.dealloc(ptr as *mut u8, layout);
+            }
+            std::thread::spawn(move || {
+                // allocating from an empty heap adopts the orphan
+                let ptr = SEGMENT_ALLOC.alloc(layout);
+                let segment = segment_of(ptr);
+                assert_ne!((*segment).thread_id.load(Ordering::Acquire), 0);
+                SEGMENT_ALLOC.dealloc(ptr, layout);
+            }).join().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_realloc() {
+        unsafe {
+            let layout = Layout::from_size_align(100, 8).unwrap();
+            let ptr = SEGMENT_ALLOC.alloc(layout);
+            assert_eq!(SEGMENT_ALLOC.realloc(ptr, layout, 110), ptr);
+            let layout = Layout::from_size_align(110, 8).unwrap();
+            core::ptr::write_bytes(ptr, 1, 110);
+            let moved = SEGMENT_ALLOC.realloc(ptr, layout, 10000);
+            assert_ne!(moved, ptr);
+            assert!((0..110).all(|i| *moved.add(i) == 1));
+            SEGMENT_ALLOC.dealloc(moved, Layout::from_size_align(10000, 8).unwrap());
+
+            // huge blocks grow and shrink without losing contents or alignment
+            let mut size = 8 << 20;
+            let layout = Layout::from_size_align(size, 2 << 20).unwrap();
+            let mut ptr = SEGMENT_ALLOC.alloc(layout);
+            *ptr = 7;
+            *ptr.add(size - 1) = 42;
+            // keep something mapped right behind it so it has to move
+            let blocker = SEGMENT_ALLOC.alloc(Layout::from_size_align(size, 8).unwrap());
+            for &new_size in &[64 << 20, 300 << 20, 5 << 20, 100] {
+                ptr = SEGMENT_ALLOC.realloc(ptr, Layout::from_size_align(size, 2 << 20).unwrap(), new_size);
+                assert!(!ptr.is_null());
+                assert_eq!(ptr as usize % (2 << 20), 0);
+                let segment = segment_of(ptr);
+                assert!((*segment).size >= ptr as usize - segment as usize + new_size);
+                assert!((*segment).size < ptr as usize - segment as usize + new_size + SEGMENT_SIZE);
+                assert_eq!(*ptr, 7);
+                if new_size > size {
+                    assert_eq!(*ptr.add(size - 1), 42);
+                }
+                *ptr.add(new_size - 1) = 42;
+                size = new_size;
+            }
+            SEGMENT_ALLOC.dealloc(ptr, Layout::from_size_align(size, 2 << 20).unwrap());
+            SEGMENT_ALLOC.dealloc(blocker, Layout::from_size_align(8 << 20, 8).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_aligned() {
+        unsafe {
+            for &align in &[128, 4096, 32768, 2 << 20, SEGMENT_SIZE, 4 * SEGMENT_SIZE, 1 << 30] {
+                let mut blocks = std::vec::Vec::new();
+                for &size in &[1, 100, 5000, 3 << 20] {
+                    let layout = Layout::from_size_align(size, align).unwrap();
+                    let ptr = SEGMENT_ALLOC.alloc(layout);
+                    assert!(!ptr.is_null());
+                    assert_eq!(ptr as usize % align, 0);
+                    core::ptr::write_bytes(ptr, 0x5a, size);
+                    blocks.push((ptr, layout));
+                }
+                let (ptr, layout) = blocks.pop().unwrap();
+                let grown = SEGMENT_ALLOC.realloc(ptr, layout, 40 << 20);
+                assert_eq!(grown as usize % align, 0);
+                assert_eq!(*grown.add((3 << 20) - 1), 0x5a);
+                SEGMENT_ALLOC.dealloc(grown, Layout::from_size_align(40 << 20, align).unwrap());
+                // interior pointers are freed from another thread as well
+                let blocks = blocks.into_iter().map(|(ptr, layout)| (ptr as usize, layout)).collect::<std::vec::Vec<_>>();
+                std::thread::spawn(move || {
+                    for (ptr, layout) in blocks {
+                        SEGMENT_ALLOC.dealloc(ptr as *mut u8, layout);
+                    }
+                }).join().unwrap();
+            }
+            // the freed interior blocks come back intact
+            let layout = Layout::from_size_align(100, 4096).unwrap();
+            let blocks: std::vec::Vec<_> = (0..100).map(|_| SEGMENT_ALLOC.alloc(layout)).collect();
+            for &ptr in &blocks {
+                assert_eq!(ptr as usize % 4096, 0);
+                SEGMENT_ALLOC.dealloc(ptr, layout);
+            }
+        }
+    }
+
+    #[test]
+    fn test_purge() {
+        unsafe {
+            let mut heap = Heap::new();
+            let blocks: std::vec::Vec<_> = (0..100).map(|_| heap.malloc(4096)).collect();
+            set_purge_delay(core::time::Duration::from_secs(3600));
+            for &ptr in &blocks[..50] {
+                heap.free(ptr);
+            }
+            // an emptied page stays committed until its delay expires
+            assert!(heap.retained > 0);
+            assert!((*heap.purge.first).purge_pending);
+            set_retain_limit(0);
+            heap.free(blocks[50]);
+            heap.purge(now_ms(), false);
+            assert_eq!(heap.retained, 0);
+            assert!(heap.purge.first.is_null());
+            set_retain_limit(16 << 20);
+            set_purge_delay(core::time::Duration::from_millis(10));
+            for &ptr in &blocks[51..] {
+                heap.free(ptr);
+            }
+            heap_done(&mut heap);
+        }
+    }
+
+    #[test]
+    fn test_collect() {
+        unsafe {
+            let layout = Layout::from_size_align(4096, 8).unwrap();
+            let blocks: std::vec::Vec<_> = (0..200).map(|_| SEGMENT_ALLOC.alloc(layout)).collect();
+            let mut ranges = std::vec::Vec::new();
+            for &ptr in &blocks {
+                core::ptr::write_bytes(ptr, 1, 4096);
+                let page = page_of(segment_of(ptr), ptr);
+                let start = align_up(page_start(page) as usize, 4096);
+                let end = (page_start(page) as usize + page_area_size(page)) & !4095;
+                if !ranges.contains(&(start, end)) {
+                    ranges.push((start, end));
+                }
+            }
+            for &ptr in &blocks {
+                SEGMENT_ALLOC.dealloc(ptr, layout);
+            }
+            collect(true);
+            for (start, end) in ranges {
+                let mut residency = std::vec![0u8; (end - start) / 4096];
+                // unmapped ranges fail with ENOMEM, which counts as released
+                if syscall!(SYS_mincore, start, end - start, residency.as_mut_ptr()).is_ok() {
+                    assert!(residency.iter().all(|&x| x & 1 == 0));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_huge_pages() {
+        unsafe {
+            for &mode in &[HugePages::Transparent, HugePages::Hugetlb] {
+                let before = huge_page_stats();
+                set_huge_pages(mode);
+                let mut heap = Heap::new();
+                let segment = heap.segment_alloc(PageType::SMALL, 0, 0);
+                set_huge_pages(HugePages::Off);
+                assert!(!segment.is_null());
+                assert_eq!(segment as usize % SEGMENT_SIZE, 0);
+                let after = huge_page_stats();
+                match mode {
+                    HugePages::Transparent => assert!(
+                        after.transparent + after.transparent_failed > before.transparent + before.transparent_failed),
+                    _ => {
+                        assert!(after.hugetlb + after.hugetlb_failed > before.hugetlb + before.hugetlb_failed);
+                        assert_eq!((*segment).hugetlb, after.hugetlb > before.hugetlb);
+                    }
+                }
+                // the mapping is usable either way
+                core::ptr::write_bytes(page_start(&(*segment).pages[1]), 1, SMALL_PAGE_SIZE);
+                segment_unmap(segment);
+            }
+        }
+    }
+
+    #[test]
+    fn test_stats() {
+        unsafe {
+            let before = stats();
+            let layout = Layout::from_size_align(100, 8).unwrap();
+            let bin = bin_index(100);
+            let blocks: std::vec::Vec<_> = (0..1000).map(|_| SEGMENT_ALLOC.alloc(layout)).collect();
+            let huge = SEGMENT_ALLOC.alloc(Layout::from_size_align(10 << 20, 8).unwrap());
+            let during = stats();
+            assert!(during.bins[bin].allocated >= before.bins[bin].allocated + 1000);
+            assert!(during.bins[0].allocated >= before.bins[0].allocated + 1);
+            assert!(during.mmap_calls > before.mmap_calls);
+            assert!(during.mapped >= 10 << 20);
+            assert!(during.committed <= during.mapped);
+            assert!(during.peak_committed >= during.committed);
+            assert!(during.peak_live >= during.live);
+            // the huge block is passed on at once; other heaps hold back at
+            // most a batch each
+            assert!(during.peak_live >= 8 << 20);
+            assert!(during.segments >= 2);
+            // live counts are shared with concurrently running tests, so only
+            // check this thread's heap exactly
+            let heap = local_heap();
+            let live = heap.stats.blocks[bin].load(Ordering::Relaxed);
+            let live_bytes = heap.stats.live.load(Ordering::Relaxed);
+            for &ptr in &blocks {
+                SEGMENT_ALLOC.dealloc(ptr, layout);
+            }
+            SEGMENT_ALLOC.dealloc(huge, Layout::from_size_align(10 << 20, 8).unwrap());
+            assert_eq!(heap.stats.blocks[bin].load(Ordering::Relaxed), live - 1000);
+            assert_eq!(heap.stats.live.load(Ordering::Relaxed),
+                       live_bytes - 1000 * bin_block_size(bin) as isize - (10 << 20));
+            assert!(stats().munmap_calls > during.munmap_calls);
+            print_stats();
+        }
+    }
+
+    #[test]
+    fn test_numa() {
+        unsafe {
+            println!("{}, {}", super::numa_count(), super::numa_count());
+        }
+    }
+    #[test]
+    fn test_numa_cache() {
+        unsafe {
+            fake_numa_count(4);
+            let mut heap = Heap::new();
+            let segment = heap.segment_alloc(PageType::SMALL, 0, 0);
+            fake_numa_count(0);
+            assert!(!segment.is_null());
+            assert!((*segment).numa_node < 4);
+            // park it on a node nothing else runs on
+            let node = NUMA_LIMIT - 1;
+            (*segment).numa_node = node;
+            segment_free(segment);
+            assert!(cache_pop(node - 1).is_null());
+            assert_eq!(cache_pop(node), segment);
+            assert!(cache_pop(node).is_null());
+            segment_unmap(segment);
+        }
+    }
+
     #[test]
     fn test_numa_node() {
         unsafe {
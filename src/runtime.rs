@@ -4,16 +4,52 @@ use syscalls::*;
 use crate::write::*;
 
 extern "C" {
-    fn main() -> isize;
+    fn main(argc: isize, argv: *const *const u8, envp: *const *const u8) -> isize;
 }
 
-
+/// Process entry point.
+///
+/// The kernel enters here with `argc` at `rsp`, followed by the NULL terminated
+/// `argv` and `envp` arrays and then the auxiliary vector. Nothing may touch the
+/// stack before that pointer is captured, so this stub only clears the frame
+/// pointer, aligns the stack and hands the original `rsp` to `start_main`.
+#[naked]
 #[no_mangle]
-unsafe extern "C" fn _start() {
+unsafe extern "C" fn _start() -> ! {
+    asm!(
+        "xor ebp, ebp",
+        "mov rdi, rsp",
+        "and rsp, -16",
+        "call {}",
+        sym start_main,
+        options(noreturn)
+    );
+}
+
+unsafe extern "C" fn start_main(sp: *const usize) -> ! {
+    let argc = *sp;
+    let argv = sp.add(1) as *const *const u8;
+    let envp = argv.add(argc + 1);
+    let mut auxv = envp;
+    while !(*auxv).is_null() {
+        auxv = auxv.add(1);
+    }
+    crate::env::init(argc, argv, envp);
+    crate::auxv::init(auxv.add(1) as *const usize);
+    crate::vdso::init();
+    crate::sync::init();
     crate::thread::init_main_thread();
-    let result = main();
+    let result = main(argc as isize, argv, envp);
+    crate::thread::run_tls_dtors();
+    // fold this heap's counters into the process totals while its control
+    // block is still there, so the report and statistics at exit include them
+    crate::memory::heap_done(&mut crate::thread::thread_self().heap);
+    #[cfg(feature = "leak-report")]
+    crate::leak::report();
     crate::thread::munmap_self();
-    syscall!(SYS_exit, result).unwrap();
+    // returning from main ends the process, whatever threads are still running
+    syscall!(SYS_exit_group, result).unwrap();
+    core::hint::unreachable_unchecked()
 }
 
 /// This function is called on panic.
@@ -22,7 +58,8 @@ fn panic(info: &PanicInfo) -> ! {
     unsafe {
         WRITER.lock()._write_str("[EXCEPTION]\n");
         crate::eprintln!("{}", info);
-        syscall!(SYS_exit, 1).unwrap();
+        // take every thread down with us, not only the one that panicked
+        syscall!(SYS_exit_group, 1).unwrap();
         core::hint::unreachable_unchecked()
     }
 }
\ No newline at end of file
@@ -6,6 +6,9 @@ use core::sync::atomic::*;
 
 use syscalls::*;
 
+use crate::time::{Timespec, CLOCK_MONOTONIC};
+use core::time::Duration;
+
 
 pub const FUTEX_WAIT: u64 = 0;
 pub const FUTEX_WAKE: u64 = 1;
@@ -49,6 +52,42 @@ pub fn futex_wait(target: &AtomicU64, target_value: u64) {
     }
 }
 
+/// Like `futex_wait`, but on a process-shared futex key.
+///
+/// The kernel's `CLONE_CHILD_CLEARTID` wakeup is a shared `FUTEX_WAKE`, which
+/// never matches a waiter queued with `FUTEX_WAIT_PRIVATE`. It compares a
+/// 32-bit word, like every futex operation.
+#[inline(always)]
+pub fn futex_wait_shared(target: &AtomicU32, target_value: u32) {
+    unsafe {
+        match syscall!(SYS_futex, target as *const AtomicU32, FUTEX_WAIT, target_value, 0, 0, 0) {
+            _ => ()
+        }
+    }
+}
+
+const ETIMEDOUT: i64 = 110;
+const EAGAIN: i64 = 11;
+
+/// Like `futex_wait`, but gives up at `deadline` on `CLOCK_MONOTONIC`.
+/// Returns `false` if the deadline passed before a wakeup.
+pub fn futex_wait_until(target: &AtomicU64, target_value: u64, deadline: &Timespec) -> bool {
+    unsafe {
+        match syscall!(SYS_futex, target as *const AtomicU64, FUTEX_WAIT_BITSET_PRIVATE, target_value,
+                       deadline as *const Timespec, 0, u32::MAX) {
+            Err(ETIMEDOUT) => false,
+            _ => true
+        }
+    }
+}
+
+/// The `CLOCK_MONOTONIC` time `timeout` from now, as `futex_wait_until` takes it.
+pub fn deadline_after(timeout: Duration) -> Timespec {
+    let now = crate::vdso::clock_gettime(CLOCK_MONOTONIC).unwrap_or_default();
+    Timespec::from_duration(now.as_duration().checked_add(timeout)
+        .unwrap_or_else(|| Duration::from_secs(i64::MAX as u64)))
+}
+
 #[inline(always)]
 pub fn futex_wake_one(target: &AtomicU64) {
     unsafe {
@@ -56,8 +95,19 @@ pub fn futex_wake_one(target: &AtomicU64) {
     }
 }
 
+#[inline(always)]
+pub fn futex_wake_all(target: &AtomicU64) {
+    unsafe {
+        match syscall!(SYS_futex, target as *const AtomicU64, FUTEX_WAKE_PRIVATE, i32::MAX, 0, 0, 0) {
+            _ => ()
+        }
+    }
+}
+
 pub struct Futex<T> {
     pub(crate) _flag: AtomicU64,
+    /// RTM skip count and penalty of this lock, see `rtm_lock`.
+    pub(crate) _elision: AtomicU32,
     pub(crate) item: core::cell::UnsafeCell<T>,
 }
 
@@ -70,7 +120,220 @@ unsafe impl<T> Sync for Futex<T> {}
 
 unsafe impl<T> Send for Futex<T> {}
 
+/// How lock acquisitions are elided, picked from CPUID once.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Elision {
+    /// No elision, plain atomics.
+    Plain,
+    /// `xacquire`/`xrelease` prefixed lock word updates.
+    Hle,
+    /// Critical sections run as `xbegin`/`xend` transactions, with elision
+    /// of a lock skipped for a while after its transactions abort.
+    Rtm,
+}
+
+// 0 until probed, then 1 + the `Elision` in use
+static ELISION: AtomicU8 = AtomicU8::new(0);
+
+fn probe_elision() -> Elision {
+    use core::arch::x86_64::*;
+    unsafe {
+        if __cpuid(0).eax < 7 {
+            return Elision::Plain;
+        }
+        let features = __cpuid_count(7, 0);
+        // RTM_ALWAYS_ABORT: the microcode disabled RTM but still reports it
+        if features.ebx & (1 << 11) != 0 && features.edx & (1 << 11) == 0 {
+            Elision::Rtm
+        } else if features.ebx & (1 << 4) != 0 {
+            Elision::Hle
+        } else {
+            Elision::Plain
+        }
+    }
+}
+
+/// Probe the CPU for lock elision support; called once at startup.
+pub fn init() {
+    ELISION.store(probe_elision() as u8 + 1, Ordering::Relaxed);
+}
+
+#[inline(always)]
+pub fn elision() -> Elision {
+    match ELISION.load(Ordering::Relaxed) {
+        0 => {
+            init();
+            elision()
+        }
+        1 => Elision::Plain,
+        2 => Elision::Hle,
+        _ => Elision::Rtm
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct LockStats {
+    pub elision: Elision,
+    /// `Futex` critical sections committed as RTM transactions. HLE gives no
+    /// such feedback.
+    pub elided: u64,
+    /// RTM transactions aborted, after which the lock was taken for real.
+    pub aborted: u64,
+    /// Acquisitions that skipped RTM because of recent aborts.
+    pub skipped: u64,
+    /// `Futex` acquisitions that had to sleep.
+    pub contended: u64,
+}
+
+#[repr(align(64))]
+struct Stripe(AtomicU64);
+
+/// Event counter split over cache lines picked by the stack address, so each
+/// thread mostly counts on a line of its own instead of one shared by all.
+struct Counter([Stripe; 8]);
+
+impl Counter {
+    const fn new() -> Self {
+        Counter([
+            Stripe(AtomicU64::new(0)), Stripe(AtomicU64::new(0)),
+            Stripe(AtomicU64::new(0)), Stripe(AtomicU64::new(0)),
+            Stripe(AtomicU64::new(0)), Stripe(AtomicU64::new(0)),
+            Stripe(AtomicU64::new(0)), Stripe(AtomicU64::new(0)),
+        ])
+    }
+
+    #[inline(always)]
+    fn add(&self, delta: u64) {
+        let sp: usize;
+        unsafe {
+            asm!("mov {}, rsp", out(reg) sp);
+        }
+        // thread stacks lie at least this far apart
+        let idx = (sp >> 16).wrapping_mul(0x9E37_79B9_7F4A_7C15) >> 61;
+        self.0[idx].0.fetch_add(delta, Ordering::Relaxed);
+    }
+
+    fn sum(&self) -> u64 {
+        self.0.iter().map(|stripe| stripe.0.load(Ordering::Relaxed)).sum()
+    }
+}
+
+static ELIDED: Counter = Counter::new();
+static ABORTED: Counter = Counter::new();
+static SKIPPED: Counter = Counter::new();
+static CONTENDED: Counter = Counter::new();
+
+pub fn lock_stats() -> LockStats {
+    LockStats {
+        elision: elision(),
+        elided: ELIDED.sum(),
+        aborted: ABORTED.sum(),
+        skipped: SKIPPED.sum(),
+        contended: CONTENDED.sum(),
+    }
+}
+
+const XBEGIN_STARTED: u32 = !0;
+// acquisitions skipping RTM after an abort, doubling while aborts keep coming
+const RTM_PENALTY_MIN: u32 = 16;
+const RTM_PENALTY_SHIFT_MAX: u32 = 8;
+// `Futex::_elision`: acquisitions left to skip in the low half, how often the
+// penalty has doubled in the high half
+const RTM_SKIP_MASK: u32 = 0xffff;
+const RTM_SHIFT: u32 = 16;
+
+/// Start a transaction. Returns `XBEGIN_STARTED` inside it, or the abort
+/// status once the transaction is rolled back.
+#[inline(always)]
+unsafe fn xbegin() -> u32 {
+    let status: u32;
+    asm!("xbegin 2f", "2:", inout("eax") XBEGIN_STARTED => status);
+    status
+}
+
+#[inline(always)]
+unsafe fn xend() {
+    asm!("xend");
+}
+
+#[inline(always)]
+unsafe fn xabort() {
+    asm!("xabort 0xff");
+}
+
+#[inline(always)]
+unsafe fn xtest() -> bool {
+    let active: u8;
+    asm!("xtest", "setnz {}", out(reg_byte) active);
+    active != 0
+}
+
+/// Run the critical section as a transaction instead of taking the lock.
+/// The lock word joins the read set, so anyone taking the lock for real
+/// aborts us. After an abort the next acquisitions of the same lock take it
+/// for real; other locks keep eliding.
+#[inline(always)]
+fn rtm_lock(flag: &AtomicU64, state: &AtomicU32) -> bool {
+    let current = state.load(Ordering::Relaxed);
+    if current & RTM_SKIP_MASK != 0 {
+        let _ = state.compare_exchange(current, current - 1, Ordering::Relaxed, Ordering::Relaxed);
+        SKIPPED.add(1);
+        return false;
+    }
+    unsafe {
+        if xbegin() == XBEGIN_STARTED {
+            if flag.load(Ordering::Relaxed) == FREE {
+                return true;
+            }
+            xabort();
+        }
+    }
+    ABORTED.add(1);
+    let shift = current >> RTM_SHIFT;
+    let next = (shift + 1).min(RTM_PENALTY_SHIFT_MAX);
+    state.store(next << RTM_SHIFT | RTM_PENALTY_MIN << shift, Ordering::Relaxed);
+    false
+}
+
+/// Commit the transaction if the lock was elided. A lock held for real is
+/// never `FREE`.
+#[inline(always)]
+fn rtm_unlock(flag: &AtomicU64, state: &AtomicU32) -> bool {
+    unsafe {
+        if flag.load(Ordering::Relaxed) != FREE || !xtest() {
+            return false;
+        }
+        xend();
+    }
+    ELIDED.add(1);
+    let current = state.load(Ordering::Relaxed);
+    if current >> RTM_SHIFT != 0 {
+        state.store(current & RTM_SKIP_MASK, Ordering::Relaxed);
+    }
+    true
+}
+
+#[inline(always)]
 fn elision_cas(target: &AtomicU64, current: u64, next: u64) -> u64 {
+    if elision() == Elision::Hle {
+        hle_cas(target, current, next)
+    } else {
+        match target.compare_exchange(current, next, Ordering::Acquire, Ordering::Relaxed) {
+            Ok(prev) | Err(prev) => prev
+        }
+    }
+}
+
+#[inline(always)]
+fn elision_fetch_sub(target: &AtomicU64, delta: u64) -> u64 {
+    if elision() == Elision::Hle {
+        hle_fetch_sub(target, delta)
+    } else {
+        target.fetch_sub(delta, Ordering::Release)
+    }
+}
+
+fn hle_cas(target: &AtomicU64, current: u64, next: u64) -> u64 {
     unsafe {
         let prev: u64;
         llvm_asm!("xacquire; lock; cmpxchgq $2, $1"
@@ -82,7 +345,7 @@ fn elision_cas(target: &AtomicU64, current: u64, next: u64) -> u64 {
     }
 }
 
-fn elision_fetch_sub(target: &AtomicU64, delta: u64) -> u64 {
+fn hle_fetch_sub(target: &AtomicU64, delta: u64) -> u64 {
     unsafe {
         let prev: u64;
         llvm_asm!("xrelease; lock; xaddq $2, $1"
@@ -98,6 +361,7 @@ impl<T> Futex<T> {
     pub fn new(item: T) -> Self {
         Futex {
             _flag: AtomicU64::new(FREE),
+            _elision: AtomicU32::new(0),
             item: UnsafeCell::new(item),
         }
     }
@@ -105,7 +369,11 @@ impl<T> Futex<T> {
     #[inline(always)]
     fn raw_lock(&self) {
         // try elision lock
-        if self._flag.load(Ordering::Relaxed) == FREE
+        if elision() == Elision::Rtm {
+            if rtm_lock(&self._flag, &self._elision) {
+                return;
+            }
+        } else if self._flag.load(Ordering::Relaxed) == FREE
             && elision_cas(&self._flag, FREE, LOCKED) == FREE {
             return;
         }
@@ -122,6 +390,7 @@ impl<T> Futex<T> {
             }
         }
         // enter futex path
+        CONTENDED.add(1);
         loop {
             if self._flag.load(Ordering::Relaxed) == FUTEX_MODE
                 || self._flag.compare_exchange_weak(LOCKED, FUTEX_MODE, Ordering::Acquire, Ordering::Relaxed).is_ok() {
@@ -133,21 +402,74 @@ impl<T> Futex<T> {
         }
     }
 
+    /// Lock for a waiter coming back from a `Condvar`. It may have been
+    /// requeued in place of other sleepers, so the word is left at
+    /// `FUTEX_MODE` for the unlock to wake the next one.
+    fn raw_lock_contended(&self) {
+        while self._flag.swap(FUTEX_MODE, Ordering::Acquire) != FREE {
+            futex_wait(&self._flag, FUTEX_MODE);
+        }
+    }
+
     #[inline(always)]
     fn raw_unlock(&self) {
+        if elision() == Elision::Rtm && rtm_unlock(&self._flag, &self._elision) {
+            return;
+        }
         if elision_fetch_sub(&self._flag, 1) == FUTEX_MODE {
             self._flag.store(FREE, Ordering::Relaxed);
             futex_wake_one(&self._flag);
         }
     }
 
+    // Spin for a while, then sleep in `FUTEX_MODE` as `raw_lock` does, but
+    // only until `deadline`.
+    fn raw_lock_until(&self, deadline: &Timespec) -> bool {
+        for _ in 0..SPIN_LIMIT {
+            if self._flag.compare_exchange_weak(FREE, LOCKED, Ordering::Acquire, Ordering::Relaxed).is_ok() {
+                return true;
+            }
+            spin_loop_hint();
+        }
+        while self._flag.swap(FUTEX_MODE, Ordering::Acquire) != FREE {
+            if !futex_wait_until(&self._flag, FUTEX_MODE, deadline) {
+                return false;
+            }
+        }
+        true
+    }
+
+    unsafe fn handle(&self) -> FutexHandle<T> {
+        FutexHandle {
+            _futex: &self,
+            item: &mut *self.item.get(),
+        }
+    }
+
     pub fn lock(&self) -> FutexHandle<T> {
         self.raw_lock();
-        unsafe {
-            FutexHandle {
-                _futex: &self,
-                item: &mut *self.item.get(),
-            }
+        unsafe { self.handle() }
+    }
+
+    pub fn try_lock(&self) -> Option<FutexHandle<T>> {
+        match self._flag.compare_exchange(FREE, LOCKED, Ordering::Acquire, Ordering::Relaxed) {
+            Ok(_) => Some(unsafe { self.handle() }),
+            Err(_) => None
+        }
+    }
+
+    /// Like `lock`, but give up and return `None` after `timeout`.
+    pub fn lock_timeout(&self, timeout: Duration) -> Option<FutexHandle<T>> {
+        self.lock_until(deadline_after(timeout))
+    }
+
+    /// Like `lock`, but give up and return `None` once `CLOCK_MONOTONIC`
+    /// reaches `deadline`.
+    pub fn lock_until(&self, deadline: Timespec) -> Option<FutexHandle<T>> {
+        if self.raw_lock_until(&deadline) {
+            Some(unsafe { self.handle() })
+        } else {
+            None
         }
     }
 }
@@ -172,8 +494,145 @@ impl<'a, T> DerefMut for FutexHandle<'a, T> {
     }
 }
 
-const WRITE_LOCKED: u64 = 0;
-const RW_OPEN: u64 = 1;
+/// Condition variable for use with a `Futex`.
+///
+/// Waiters sleep on a sequence number that every notification bumps, so a
+/// notification sent between unlocking and going to sleep is not lost.
+pub struct Condvar {
+    seq: AtomicU64,
+    // lock word of the futex the waiters hold, to requeue them onto
+    mutex: AtomicPtr<AtomicU64>,
+}
+
+unsafe impl Sync for Condvar {}
+
+unsafe impl Send for Condvar {}
+
+impl Condvar {
+    pub const fn new() -> Self {
+        Condvar {
+            seq: AtomicU64::new(0),
+            mutex: AtomicPtr::new(core::ptr::null_mut()),
+        }
+    }
+
+    /// Release the lock, sleep until notified and take the lock again.
+    /// Wakeups can be spurious; see `wait_while`.
+    pub fn wait<'a, T>(&self, handle: FutexHandle<'a, T>) -> FutexHandle<'a, T> {
+        let futex = handle._futex;
+        let flag = &futex._flag as *const AtomicU64 as *mut AtomicU64;
+        match self.mutex.compare_exchange(core::ptr::null_mut(), flag, Ordering::Relaxed, Ordering::Relaxed) {
+            Err(other) if other != flag => panic!("condvar used with more than one futex"),
+            _ => ()
+        }
+        let seq = self.seq.load(Ordering::Relaxed);
+        drop(handle);
+        futex_wait(&self.seq, seq);
+        futex.raw_lock_contended();
+        FutexHandle {
+            _futex: futex,
+            item: unsafe { &mut *futex.item.get() },
+        }
+    }
+
+    /// Wait for as long as `condition` holds on the protected value.
+    pub fn wait_while<'a, T, F>(&self, mut handle: FutexHandle<'a, T>, mut condition: F) -> FutexHandle<'a, T>
+        where F: FnMut(&mut T) -> bool {
+        while condition(&mut *handle) {
+            handle = self.wait(handle);
+        }
+        handle
+    }
+
+    pub fn notify_one(&self) {
+        self.seq.fetch_add(1, Ordering::Release);
+        futex_wake_one(&self.seq);
+    }
+
+    /// Wake one waiter and move the rest onto the futex, where each unlock
+    /// hands the lock to the next instead of all of them racing for it.
+    pub fn notify_all(&self) {
+        let mutex = self.mutex.load(Ordering::Relaxed);
+        let mut seq = self.seq.fetch_add(1, Ordering::Release) + 1;
+        if mutex.is_null() {
+            // nobody has waited yet
+            return;
+        }
+        loop {
+            match unsafe {
+                syscall!(SYS_futex, &self.seq as *const AtomicU64, FUTEX_CMP_REQUEUE_PRIVATE,
+                         1, i32::MAX, mutex, seq)
+            } {
+                Ok(_) => return,
+                // another notification got in between, requeue behind it
+                Err(EAGAIN) => seq = self.seq.load(Ordering::Relaxed),
+                // cannot requeue: wake everybody and let them queue on the mutex
+                Err(_) => return futex_wake_all(&self.seq),
+            }
+        }
+    }
+}
+
+// `RwFutex` lock word, kept in the low 32 bits the kernel compares: a reader
+// count, all ones for a writer, a bit for the upgradable reader among the
+// readers, plus a bit for each kind of sleeper.
+const RW_MASK: u64 = (1 << 28) - 1;
+const READ_LOCKED: u64 = 1;
+const WRITE_LOCKED: u64 = RW_MASK;
+const MAX_READERS: u64 = RW_MASK - 1;
+const UPGRADABLE: u64 = 1 << 28;
+const UPGRADERS_WAITING: u64 = 1 << 29;
+const READERS_WAITING: u64 = 1 << 30;
+const WRITERS_WAITING: u64 = 1 << 31;
+
+// futex bitsets of the wait queues sharing the lock word
+const READER_QUEUE: u32 = 1;
+const WRITER_QUEUE: u32 = 2;
+// waiting for the upgradable reader to go
+const UPGRADER_QUEUE: u32 = 4;
+// the upgradable reader waiting for the other readers to go
+const UPGRADE_QUEUE: u32 = 8;
+
+#[inline(always)]
+fn is_unlocked(state: u64) -> bool {
+    state & RW_MASK == 0
+}
+
+#[inline(always)]
+fn is_write_locked(state: u64) -> bool {
+    state & RW_MASK == WRITE_LOCKED
+}
+
+// Waiting writers keep new readers out, so a stream of readers cannot starve
+// them.
+#[inline(always)]
+fn is_read_lockable(state: u64) -> bool {
+    state & RW_MASK < MAX_READERS && state & (READERS_WAITING | WRITERS_WAITING) == 0
+}
+
+/// Sleep on one of the `queue`s sharing `target` while it holds
+/// `target_value`. Returns `false` once `deadline` on `CLOCK_MONOTONIC` has
+/// passed.
+pub fn futex_wait_queue(target: &AtomicU64, target_value: u64, queue: u32, deadline: Option<&Timespec>) -> bool {
+    let timeout = deadline.map_or(core::ptr::null(), |deadline| deadline as *const Timespec);
+    unsafe {
+        match syscall!(SYS_futex, target as *const AtomicU64, FUTEX_WAIT_BITSET_PRIVATE, target_value,
+                       timeout, 0, queue) {
+            Err(ETIMEDOUT) => false,
+            _ => true
+        }
+    }
+}
+
+/// Wake up to `count` sleepers of `queue`; returns whether any woke.
+pub fn futex_wake_queue(target: &AtomicU64, count: i32, queue: u32) -> bool {
+    unsafe {
+        match syscall!(SYS_futex, target as *const AtomicU64, FUTEX_WAKE_BITSET_PRIVATE, count, 0, 0, queue) {
+            Ok(woken) => woken > 0,
+            Err(_) => false
+        }
+    }
+}
 
 pub struct RwFutex<T> {
     pub(crate) _flag: AtomicU64,
@@ -190,6 +649,14 @@ pub struct RwFutexReadHandle<'a, T> {
     item: &'a T,
 }
 
+/// A read lock that can be upgraded to the write lock without letting a
+/// writer in between. It shares the lock with plain readers, but not with
+/// another upgradable reader.
+pub struct RwFutexUpgradableHandle<'a, T> {
+    _futex: &'a RwFutex<T>,
+    item: &'a T,
+}
+
 unsafe impl<T> Sync for RwFutex<T> {}
 
 unsafe impl<T> Send for RwFutex<T> {}
@@ -197,117 +664,388 @@ unsafe impl<T> Send for RwFutex<T> {}
 impl<T> RwFutex<T> {
     fn new(item: T) -> Self {
         RwFutex {
-            _flag: AtomicU64::new(RW_OPEN),
+            _flag: AtomicU64::new(0),
             item: UnsafeCell::new(item),
         }
     }
+
     #[inline(always)]
-    fn raw_unlock(&self) {
-        let mut wanted: u64;
-        let mut current: u64;
+    fn spin_until<F: Fn(u64) -> bool>(&self, done: F) -> u64 {
+        let mut counter = 0;
         loop {
-            current = self._flag.load(Ordering::Relaxed);
-            if current == RW_OPEN {
-                return;
-            }
-            if current == WRITE_LOCKED {
-                wanted = RW_OPEN;
-            } else {
-                wanted = current - 1;
-            }
-            if self._flag.compare_exchange_weak(current, wanted, Ordering::Release, Ordering::Relaxed).is_ok() {
-                break;
+            let state = self._flag.load(Ordering::Relaxed);
+            if done(state) || counter == SPIN_LIMIT {
+                return state;
             }
             spin_loop_hint();
+            counter += 1;
         }
-        futex_wake_one(&self._flag);
+    }
+
+    #[inline(always)]
+    fn spin_read(&self) -> u64 {
+        self.spin_until(|state| !is_write_locked(state) || state & (READERS_WAITING | WRITERS_WAITING) != 0)
+    }
+
+    #[inline(always)]
+    fn spin_write(&self) -> u64 {
+        self.spin_until(|state| is_unlocked(state) || state & WRITERS_WAITING != 0)
     }
 
     #[inline(always)]
     fn raw_read_lock(&self) {
-        let mut current: u64 = self._flag.load(Ordering::Relaxed);
-        if current == RW_OPEN && elision_cas(&self._flag, current, current + 1) == current {
+        let state = self._flag.load(Ordering::Relaxed);
+        if is_read_lockable(state) && elision_cas(&self._flag, state, state + READ_LOCKED) == state {
             return;
         }
-        let mut counter = 0;
+        self.read_contended(None);
+    }
+
+    /// Returns `false` if `deadline` passed first.
+    #[cold]
+    fn read_contended(&self, deadline: Option<&Timespec>) -> bool {
+        let mut state = self.spin_read();
         loop {
-            current = self._flag.load(Ordering::Relaxed);
-            if current == WRITE_LOCKED || self._flag.compare_exchange_weak(current, current + 1, Ordering::Acquire, Ordering::Relaxed).is_err() {
-                if counter <= SPIN_LIMIT {
-                    spin_loop_hint();
-                    counter += 1;
-                    continue;
-                }
-                while unsafe { syscall!(SYS_futex, &self._flag as *const AtomicU64, FUTEX_WAIT_PRIVATE, current, 0, 0, 0).is_err() } {
-                    if self._flag.load(Ordering::Relaxed) >= RW_OPEN {
-                        break;
+            if is_read_lockable(state) {
+                match self._flag.compare_exchange_weak(state, state + READ_LOCKED, Ordering::Acquire, Ordering::Relaxed) {
+                    Ok(_) => return true,
+                    Err(current) => {
+                        state = current;
+                        continue;
                     }
-                    spin_loop_hint();
                 }
-            } else {
-                break;
             }
+            if state & RW_MASK == MAX_READERS {
+                panic!("too many readers on a RwFutex");
+            }
+            // announce ourselves before sleeping, so the unlock wakes us
+            if state & READERS_WAITING == 0 {
+                if let Err(current) = self._flag.compare_exchange(state, state | READERS_WAITING, Ordering::Relaxed, Ordering::Relaxed) {
+                    state = current;
+                    continue;
+                }
+            }
+            if !futex_wait_queue(&self._flag, state | READERS_WAITING, READER_QUEUE, deadline) {
+                return false;
+            }
+            state = self.spin_read();
         }
     }
 
     #[inline(always)]
     fn raw_write_lock(&self) {
-        if self._flag.load(Ordering::Relaxed) == RW_OPEN && elision_cas(&self._flag, RW_OPEN, WRITE_LOCKED) == RW_OPEN {
+        if self._flag.load(Ordering::Relaxed) == 0 && elision_cas(&self._flag, 0, WRITE_LOCKED) == 0 {
             return;
         }
-        let mut counter = 0;
+        self.write_contended(None);
+    }
+
+    /// Returns `false` if `deadline` passed first.
+    #[cold]
+    fn write_contended(&self, deadline: Option<&Timespec>) -> bool {
+        let mut state = self.spin_write();
+        // once we have slept, other writers may be asleep too, but the bit
+        // announcing them was cleared to wake us: set it again with the lock
+        let mut other_writers = 0;
         loop {
-            match self._flag.compare_exchange_weak(RW_OPEN, WRITE_LOCKED, Ordering::Acquire, Ordering::Relaxed) {
-                Ok(_) => break,
-                Err(current) => {
-                    if counter <= SPIN_LIMIT {
-                        spin_loop_hint();
-                        counter += 1;
+            if is_unlocked(state) {
+                match self._flag.compare_exchange_weak(state, state | WRITE_LOCKED | other_writers, Ordering::Acquire, Ordering::Relaxed) {
+                    Ok(_) => return true,
+                    Err(current) => {
+                        state = current;
                         continue;
                     }
-                    while unsafe { syscall!(SYS_futex, &self._flag as *const AtomicU64, FUTEX_WAIT_PRIVATE, current, 0, 0, 0).is_err() } {
-                        if self._flag.load(Ordering::Relaxed) == RW_OPEN { break; }
-                        spin_loop_hint();
+                }
+            }
+            if state & WRITERS_WAITING == 0 {
+                if let Err(current) = self._flag.compare_exchange(state, state | WRITERS_WAITING, Ordering::Relaxed, Ordering::Relaxed) {
+                    state = current;
+                    continue;
+                }
+            }
+            other_writers = WRITERS_WAITING;
+            if !futex_wait_queue(&self._flag, state | WRITERS_WAITING, WRITER_QUEUE, deadline) {
+                return false;
+            }
+            state = self.spin_write();
+        }
+    }
+
+    #[cold]
+    fn upgradable_contended(&self) {
+        let mut state = self.spin_read();
+        // see `write_contended`
+        let mut other_upgraders = 0;
+        loop {
+            if is_read_lockable(state) && state & UPGRADABLE == 0 {
+                let next = state + READ_LOCKED + UPGRADABLE + other_upgraders;
+                match self._flag.compare_exchange_weak(state, next, Ordering::Acquire, Ordering::Relaxed) {
+                    Ok(_) => return,
+                    Err(current) => {
+                        state = current;
+                        continue;
                     }
-                    if self._flag.load(Ordering::Relaxed) != RW_OPEN {
-                        futex_wake_one(&self._flag);
-                        unsafe {
-                            match syscall!(SYS_sched_yield) {
-                                _ => ()
-                            }
+                }
+            }
+            if state & RW_MASK == MAX_READERS {
+                panic!("too many readers on a RwFutex");
+            }
+            // behind another upgradable reader, or behind a writer like any reader
+            let (waiting, queue) = if is_read_lockable(state) {
+                (UPGRADERS_WAITING, UPGRADER_QUEUE)
+            } else {
+                (READERS_WAITING, READER_QUEUE)
+            };
+            if state & waiting == 0 {
+                if let Err(current) = self._flag.compare_exchange(state, state | waiting, Ordering::Relaxed, Ordering::Relaxed) {
+                    state = current;
+                    continue;
+                }
+            }
+            if waiting == UPGRADERS_WAITING {
+                other_upgraders = UPGRADERS_WAITING;
+            }
+            futex_wait_queue(&self._flag, state | waiting, queue, None);
+            state = self.spin_read();
+        }
+    }
+
+    /// Turn our upgradable read lock into the write lock once the other
+    /// readers are gone. New readers are held back meanwhile.
+    fn raw_upgrade(&self) {
+        let mut state = self._flag.load(Ordering::Relaxed);
+        loop {
+            if state & RW_MASK == READ_LOCKED {
+                let next = (state & !(RW_MASK | UPGRADABLE | UPGRADERS_WAITING)) | WRITE_LOCKED;
+                match self._flag.compare_exchange_weak(state, next, Ordering::Acquire, Ordering::Relaxed) {
+                    Ok(_) => {
+                        if state & UPGRADERS_WAITING != 0 {
+                            futex_wake_queue(&self._flag, 1, UPGRADER_QUEUE);
                         }
+                        return;
+                    }
+                    Err(current) => {
+                        state = current;
+                        continue;
                     }
                 }
             }
+            if state & WRITERS_WAITING == 0 {
+                if let Err(current) = self._flag.compare_exchange(state, state | WRITERS_WAITING, Ordering::Relaxed, Ordering::Relaxed) {
+                    state = current;
+                    continue;
+                }
+            }
+            futex_wait_queue(&self._flag, state | WRITERS_WAITING, UPGRADE_QUEUE, None);
+            state = self.spin_until(|state| state & RW_MASK == READ_LOCKED);
         }
     }
 
-    fn read_lock(&self) -> RwFutexReadHandle<T> {
-        self.raw_read_lock();
+    #[inline(always)]
+    fn read_unlock(&self) {
+        let state = elision_fetch_sub(&self._flag, READ_LOCKED) - READ_LOCKED;
+        // readers only ever wait behind a writer here, and that writer waits
+        // for the last reader
+        if state & WRITERS_WAITING != 0 {
+            if is_unlocked(state) {
+                self.wake_writer_or_readers(state);
+            } else if state & RW_MASK == READ_LOCKED && state & UPGRADABLE != 0 {
+                // the one left may be waiting to upgrade
+                futex_wake_queue(&self._flag, 1, UPGRADE_QUEUE);
+            }
+        }
+    }
+
+    fn upgradable_unlock(&self) {
+        let mut state = self._flag.fetch_sub(READ_LOCKED + UPGRADABLE, Ordering::Release) - READ_LOCKED - UPGRADABLE;
+        while state & UPGRADERS_WAITING != 0 {
+            match self._flag.compare_exchange(state, state & !UPGRADERS_WAITING, Ordering::Relaxed, Ordering::Relaxed) {
+                Ok(_) => {
+                    futex_wake_queue(&self._flag, 1, UPGRADER_QUEUE);
+                    state &= !UPGRADERS_WAITING;
+                }
+                Err(current) => state = current
+            }
+        }
+        if is_unlocked(state) && state & WRITERS_WAITING != 0 {
+            self.wake_writer_or_readers(state);
+        }
+    }
+
+    /// Trade the write lock for a read lock. Readers held back by us may come
+    /// in, unless a writer is waiting too.
+    fn raw_downgrade(&self) {
+        let state = self._flag.fetch_sub(WRITE_LOCKED - READ_LOCKED, Ordering::Release) - (WRITE_LOCKED - READ_LOCKED);
+        if state & READERS_WAITING != 0 && state & WRITERS_WAITING == 0
+            && self._flag.compare_exchange(state, state & !READERS_WAITING, Ordering::Relaxed, Ordering::Relaxed).is_ok() {
+            futex_wake_queue(&self._flag, i32::MAX, READER_QUEUE);
+        }
+    }
+
+    #[inline(always)]
+    fn write_unlock(&self) {
+        let state = elision_fetch_sub(&self._flag, WRITE_LOCKED) - WRITE_LOCKED;
+        if state & (READERS_WAITING | WRITERS_WAITING) != 0 {
+            self.wake_writer_or_readers(state);
+        }
+    }
+
+    /// Hand an unlocked word to one sleeping writer or else to all sleeping
+    /// readers. The waiting bit is cleared before the wakeup, so a sleeper
+    /// that has not gone to sleep yet finds the word changed instead of
+    /// missing it. If another thread locks in between, its unlock takes over.
+    #[cold]
+    fn wake_writer_or_readers(&self, mut state: u64) {
+        if state == WRITERS_WAITING {
+            match self._flag.compare_exchange(state, 0, Ordering::Relaxed, Ordering::Relaxed) {
+                Ok(_) => {
+                    futex_wake_queue(&self._flag, 1, WRITER_QUEUE);
+                    return;
+                }
+                Err(current) => state = current
+            }
+        }
+        if state == READERS_WAITING | WRITERS_WAITING {
+            if self._flag.compare_exchange(state, READERS_WAITING, Ordering::Relaxed, Ordering::Relaxed).is_err() {
+                return;
+            }
+            if futex_wake_queue(&self._flag, 1, WRITER_QUEUE) {
+                return;
+            }
+            // the writers were gone, the readers may go instead
+            state = READERS_WAITING;
+        }
+        if state == READERS_WAITING
+            && self._flag.compare_exchange(state, 0, Ordering::Relaxed, Ordering::Relaxed).is_ok() {
+            futex_wake_queue(&self._flag, i32::MAX, READER_QUEUE);
+        }
+    }
+
+    unsafe fn read_handle(&self) -> RwFutexReadHandle<T> {
         RwFutexReadHandle {
             _futex: &self,
-            item: unsafe { &*self.item.get() },
+            item: &*self.item.get(),
         }
     }
 
+    unsafe fn write_handle(&self) -> RwFutexWriteHandle<T> {
+        RwFutexWriteHandle {
+            _futex: &self,
+            item: &mut *self.item.get(),
+        }
+    }
+
+    fn read_lock(&self) -> RwFutexReadHandle<T> {
+        self.raw_read_lock();
+        unsafe { self.read_handle() }
+    }
+
     fn write_lock(&self) -> RwFutexWriteHandle<T> {
         self.raw_write_lock();
-        RwFutexWriteHandle {
+        unsafe { self.write_handle() }
+    }
+
+    pub fn upgradable_read(&self) -> RwFutexUpgradableHandle<T> {
+        let state = self._flag.load(Ordering::Relaxed);
+        if !(is_read_lockable(state) && state & UPGRADABLE == 0
+            && self._flag.compare_exchange(state, state + READ_LOCKED + UPGRADABLE, Ordering::Acquire, Ordering::Relaxed).is_ok()) {
+            self.upgradable_contended();
+        }
+        RwFutexUpgradableHandle {
             _futex: &self,
-            item: unsafe { &mut *self.item.get() },
+            item: unsafe { &*self.item.get() },
+        }
+    }
+
+    pub fn try_read(&self) -> Option<RwFutexReadHandle<T>> {
+        let mut state = self._flag.load(Ordering::Relaxed);
+        while is_read_lockable(state) {
+            match self._flag.compare_exchange_weak(state, state + READ_LOCKED, Ordering::Acquire, Ordering::Relaxed) {
+                Ok(_) => return Some(unsafe { self.read_handle() }),
+                Err(current) => state = current
+            }
+        }
+        None
+    }
+
+    pub fn try_write(&self) -> Option<RwFutexWriteHandle<T>> {
+        let mut state = self._flag.load(Ordering::Relaxed);
+        while is_unlocked(state) {
+            match self._flag.compare_exchange_weak(state, state | WRITE_LOCKED, Ordering::Acquire, Ordering::Relaxed) {
+                Ok(_) => return Some(unsafe { self.write_handle() }),
+                Err(current) => state = current
+            }
+        }
+        None
+    }
+
+    pub fn read_timeout(&self, timeout: Duration) -> Option<RwFutexReadHandle<T>> {
+        self.read_until(deadline_after(timeout))
+    }
+
+    /// Take a read lock, or return `None` once `CLOCK_MONOTONIC` reaches `deadline`.
+    pub fn read_until(&self, deadline: Timespec) -> Option<RwFutexReadHandle<T>> {
+        match self.try_read() {
+            None if self.read_contended(Some(&deadline)) => Some(unsafe { self.read_handle() }),
+            handle => handle
+        }
+    }
+
+    pub fn write_timeout(&self, timeout: Duration) -> Option<RwFutexWriteHandle<T>> {
+        self.write_until(deadline_after(timeout))
+    }
+
+    /// Take the write lock, or return `None` once `CLOCK_MONOTONIC` reaches `deadline`.
+    pub fn write_until(&self, deadline: Timespec) -> Option<RwFutexWriteHandle<T>> {
+        match self.try_write() {
+            None if self.write_contended(Some(&deadline)) => Some(unsafe { self.write_handle() }),
+            handle => handle
         }
     }
 }
 
 impl<'a, T> Drop for RwFutexReadHandle<'a, T> {
     fn drop(&mut self) {
-        self._futex.raw_unlock();
+        self._futex.read_unlock();
     }
 }
 
 impl<'a, T> Drop for RwFutexWriteHandle<'a, T> {
     fn drop(&mut self) {
-        self._futex.raw_unlock();
+        self._futex.write_unlock();
+    }
+}
+
+impl<'a, T> Drop for RwFutexUpgradableHandle<'a, T> {
+    fn drop(&mut self) {
+        self._futex.upgradable_unlock();
+    }
+}
+
+impl<'a, T> RwFutexUpgradableHandle<'a, T> {
+    /// Wait for the other readers to leave and take the write lock.
+    pub fn upgrade(self) -> RwFutexWriteHandle<'a, T> {
+        let futex = self._futex;
+        core::mem::forget(self);
+        futex.raw_upgrade();
+        unsafe { futex.write_handle() }
+    }
+}
+
+impl<'a, T> RwFutexWriteHandle<'a, T> {
+    pub fn downgrade(self) -> RwFutexReadHandle<'a, T> {
+        let futex = self._futex;
+        core::mem::forget(self);
+        futex.raw_downgrade();
+        unsafe { futex.read_handle() }
+    }
+}
+
+impl<'a, T> Deref for RwFutexUpgradableHandle<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        return self.item;
     }
 }
 
@@ -343,6 +1081,250 @@ mod test {
     use std::thread::sleep;
     use std::time::Duration;
 
+    #[test]
+    fn test_condvar_queue() {
+        let queue = Arc::new((Futex::new(std::collections::VecDeque::<u64>::new()), super::Condvar::new()));
+        let consumers: Vec<_> = (0..4).map(|_| {
+            let queue = queue.clone();
+            std::thread::spawn(move || {
+                let mut sum = 0u64;
+                loop {
+                    let mut items = queue.1.wait_while(queue.0.lock(), |items| items.is_empty());
+                    match items.pop_front().unwrap() {
+                        0 => return sum,
+                        item => sum += item,
+                    }
+                }
+            })
+        }).collect();
+        for item in 1..=10000u64 {
+            queue.0.lock().push_back(item);
+            queue.1.notify_one();
+        }
+        for _ in 0..4 {
+            queue.0.lock().push_back(0);
+            queue.1.notify_one();
+        }
+        let sum: u64 = consumers.into_iter().map(|consumer| consumer.join().unwrap()).sum();
+        assert_eq!(sum, 10000 * 10001 / 2);
+    }
+
+    #[test]
+    fn test_condvar_notify_all() {
+        let state = Arc::new((Futex::new((false, 0)), super::Condvar::new()));
+        let waiters: Vec<_> = (0..16).map(|_| {
+            let state = state.clone();
+            std::thread::spawn(move || {
+                let mut handle = state.1.wait_while(state.0.lock(), |(ready, _)| !*ready);
+                handle.1 += 1;
+            })
+        }).collect();
+        sleep(Duration::from_millis(50));
+        state.0.lock().0 = true;
+        state.1.notify_all();
+        for waiter in waiters {
+            waiter.join().unwrap();
+        }
+        assert_eq!(state.0.lock().1, 16);
+    }
+
+    #[test]
+    fn test_try_lock() {
+        let data = Arc::new(Futex::new(0));
+        let (sender, receiver) = mpsc::channel();
+        let holder = {
+            let data = data.clone();
+            std::thread::spawn(move || {
+                let mut handle = data.try_lock().unwrap();
+                sender.send(()).unwrap();
+                sleep(Duration::from_millis(100));
+                *handle += 1;
+            })
+        };
+        receiver.recv().unwrap();
+        assert!(data.try_lock().is_none());
+        let start = std::time::Instant::now();
+        assert!(data.lock_timeout(Duration::from_millis(30)).is_none());
+        assert!(start.elapsed() >= Duration::from_millis(30));
+        *data.lock_timeout(Duration::from_secs(10)).unwrap() += 1;
+        holder.join().unwrap();
+        assert_eq!(*data.try_lock().unwrap(), 2);
+    }
+
+    #[test]
+    fn test_rw_try_lock() {
+        let data = Arc::new(RwFutex::new(0));
+        {
+            let first = data.try_read().unwrap();
+            let second = data.read_timeout(Duration::from_millis(10)).unwrap();
+            assert!(data.try_write().is_none());
+            assert!(data.write_timeout(Duration::from_millis(30)).is_none());
+        }
+        let writer = data.try_write().unwrap();
+        assert!(data.try_read().is_none());
+        assert!(data.read_timeout(Duration::from_millis(30)).is_none());
+        let reader = {
+            let data = data.clone();
+            std::thread::spawn(move || *data.read_timeout(Duration::from_secs(10)).unwrap())
+        };
+        sleep(Duration::from_millis(20));
+        drop(writer);
+        assert_eq!(reader.join().unwrap(), 0);
+        assert!(data.write_timeout(Duration::from_millis(10)).is_some());
+    }
+
+    #[test]
+    fn test_elision() {
+        assert_eq!(elision(), probe_elision());
+        let data = Arc::new(Futex::new(0));
+        let handles: Vec<_> = (0..4).map(|_| {
+            let data = data.clone();
+            std::thread::spawn(move || {
+                for _ in 0..10000 {
+                    *data.lock() += 1;
+                }
+            })
+        }).collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        assert_eq!(*data.lock(), 40000);
+        let stats = lock_stats();
+        assert_eq!(stats.elision, elision());
+        if stats.elision != Elision::Rtm {
+            assert_eq!(stats.elided + stats.aborted + stats.skipped, 0);
+        }
+    }
+
+    #[test]
+    fn test_counter() {
+        let counter = Arc::new(Counter::new());
+        let handles: Vec<_> = (0..4).map(|_| {
+            let counter = counter.clone();
+            std::thread::spawn(move || {
+                for _ in 0..10000 {
+                    counter.add(1);
+                }
+            })
+        }).collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        assert_eq!(counter.sum(), 40000);
+    }
+
+    #[test]
+    fn test_rwfutex_stress() {
+        // writers keep both halves equal; readers must never see them differ,
+        // and must not keep the writers out
+        let data = Arc::new(RwFutex::new((0u64, 0u64)));
+        let done = Arc::new(AtomicBool::new(false));
+        let readers: Vec<_> = (0..8).map(|_| {
+            let data = data.clone();
+            let done = done.clone();
+            std::thread::spawn(move || {
+                let mut reads = 0u64;
+                while !done.load(Ordering::Relaxed) {
+                    let handle = data.read_lock();
+                    assert_eq!(handle.0, handle.1);
+                    reads += 1;
+                }
+                reads
+            })
+        }).collect();
+        let writers: Vec<_> = (0..4).map(|i| {
+            let data = data.clone();
+            std::thread::spawn(move || {
+                for round in 0..2000 {
+                    let mut handle = if round % 2 == 0 {
+                        data.write_lock()
+                    } else {
+                        data.write_timeout(Duration::from_secs(60)).unwrap()
+                    };
+                    handle.0 += 1;
+                    if round % 100 == i {
+                        std::thread::yield_now();
+                    }
+                    handle.1 += 1;
+                }
+            })
+        }).collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        done.store(true, Ordering::Relaxed);
+        let reads: u64 = readers.into_iter().map(|reader| reader.join().unwrap()).sum();
+        assert!(reads > 0);
+        assert_eq!(*data.read_lock(), (8000, 8000));
+        assert_eq!(data._flag.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn test_rwfutex_upgradable() {
+        let data = Arc::new(RwFutex::new(0u64));
+        {
+            let upgradable = data.upgradable_read();
+            // plain readers get in, writers do not
+            assert!(data.try_read().is_some());
+            assert!(data.try_write().is_none());
+            let other = {
+                let data = data.clone();
+                std::thread::spawn(move || *data.upgradable_read())
+            };
+            let (sender, receiver) = mpsc::channel();
+            let release = {
+                let data = data.clone();
+                std::thread::spawn(move || {
+                    let reader = data.read_lock();
+                    sender.send(()).unwrap();
+                    sleep(Duration::from_millis(30));
+                    assert_eq!(*reader, 0);
+                })
+            };
+            receiver.recv().unwrap();
+            let mut writer = upgradable.upgrade();
+            release.join().unwrap();
+            *writer += 1;
+            let reader = writer.downgrade();
+            assert_eq!(*reader, 1);
+            assert!(data.try_write().is_none());
+            drop(reader);
+            assert_eq!(other.join().unwrap(), 1);
+        }
+        {
+            let reader = data.write_lock().downgrade();
+            assert!(data.try_read().is_some());
+            assert!(data.try_write().is_none());
+        }
+        // read, decide and modify without losing the value in between
+        let workers: Vec<_> = (0..6).map(|i| {
+            let data = data.clone();
+            std::thread::spawn(move || {
+                for _ in 0..2000 {
+                    match i % 3 {
+                        0 => {
+                            let upgradable = data.upgradable_read();
+                            let seen = *upgradable;
+                            let mut writer = upgradable.upgrade();
+                            assert_eq!(*writer, seen);
+                            *writer += 1;
+                        }
+                        1 => {
+                            let before = *data.read_lock();
+                            assert!(*data.read_lock() >= before);
+                        }
+                        _ => *data.write_lock() += 1
+                    }
+                }
+            })
+        }).collect();
+        for worker in workers {
+            worker.join().unwrap();
+        }
+        assert_eq!(*data.read_lock(), 8001);
+        assert_eq!(data._flag.load(Ordering::Relaxed), 0);
+    }
+
     #[bench]
     fn test_futex(bencher: &mut Bencher) {
         bencher.iter(|| {
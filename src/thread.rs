@@ -1,48 +1,509 @@
-use crate::memory::NAIVE_ALLOC;
+use crate::memory::{NAIVE_ALLOC, Heap, heap_init, heap_done};
+use crate::flag;
+use crate::elf::{Elf64Phdr, PT_TLS};
+use crate::sync::futex_wait_shared;
+use alloc::boxed::Box;
 use core::alloc::*;
+use core::marker::PhantomData;
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
 use syscalls::*;
 
 pub const ARCH_SET_GS : u64 = 		0x1001;
 pub const ARCH_SET_FS : u64 = 		0x1002;
 pub const ARCH_GET_FS : u64 = 		0x1003;
 pub const ARCH_GET_GS : u64 = 		0x1004;
+
+const SIG_BLOCK : u64 = 0;
+
+pub const DEFAULT_STACK_SIZE : usize = 2 << 20;
+
+const RUNNING : u64 = 0;
+const FINISHED : u64 = 1;
+const DETACHED : u64 = 2;
+
+const CLONE_THREAD_FLAGS : u64 = flag::CLONE_VM | flag::CLONE_FS | flag::CLONE_FILES
+    | flag::CLONE_SIGHAND | flag::CLONE_THREAD | flag::CLONE_SYSVSEM | flag::CLONE_SETTLS
+    | flag::CLONE_CHILD_CLEARTID | flag::CLONE_PARENT_SETTID;
+
 // thread control block
 
 #[repr(C)]
 pub struct Thread {
     pub ppid: u64,
-    pub tid: u64,
+    pub tid: u32,
     tls_map: *const [u8],
     tls_block_start: *mut u8,
-    tls_dtor_list: *mut u8,
-    local_free_list: [*mut u8; 32]
+    tls_dtor_list: *mut TlsDtor,
+    stack_map: *const [u8],
+    join_state: AtomicU64,
+    pub(crate) heap: Heap,
 }
 
+struct TlsDtor {
+    dtor: unsafe extern "C" fn(*mut u8),
+    obj: *mut u8,
+    next: *mut TlsDtor,
+}
+
+// The thread pointer points at a `PaddedThread`, whose first word must hold
+// its own address: code using the TLS models reads `%fs:0` and addresses
+// thread locals at negative offsets from it (x86_64 TLS variant II).
 #[repr(C)]
 pub struct PaddedThread {
-    __thread: *mut Thread,
+    __self: *mut PaddedThread,
     thread: Thread
 }
 
-pub unsafe fn init_main_thread() {
-    let tcb = NAIVE_ALLOC.alloc(Layout::new::<PaddedThread>()) as *mut PaddedThread;
+// static TLS template taken from the PT_TLS segment of the executable
+struct TlsImage {
+    init: *const u8,
+    init_size: usize,
+    size: usize,
+    align: usize,
+    // distance from the thread pointer down to the start of the TLS block
+    offset: usize,
+}
+
+static mut TLS_IMAGE : TlsImage = TlsImage {
+    init: core::ptr::null(),
+    init_size: 0,
+    size: 0,
+    align: 1,
+    offset: 0,
+};
+
+impl TlsImage {
+    fn new(header: &Elf64Phdr, load_bias: usize) -> Self {
+        let init = load_bias.wrapping_add(header.p_vaddr as usize);
+        let align = (header.p_align as usize).max(1);
+        let size = header.p_memsz as usize;
+        TlsImage {
+            init: init as *const u8,
+            init_size: header.p_filesz as usize,
+            size,
+            align,
+            // keep the block congruent to the image modulo its alignment
+            offset: size + (0usize.wrapping_sub(size).wrapping_sub(init) & (align - 1)),
+        }
+    }
+
+    /// Room for the TLS block and a `PaddedThread` aligned after it.
+    fn layout(&self) -> Layout {
+        let align = self.align.max(core::mem::align_of::<PaddedThread>());
+        unsafe {
+            Layout::from_size_align_unchecked(
+                self.offset + align - 1 + core::mem::size_of::<PaddedThread>(),
+                align,
+            )
+        }
+    }
+
+    /// Where the thread pointer goes in an allocation of `layout()` at `base`.
+    fn thread_pointer(&self, base: usize) -> usize {
+        let align = self.layout().align();
+        (base + self.offset + align - 1) & !(align - 1)
+    }
+}
+
+unsafe fn init_tls_image() {
+    if let Some(header) = crate::auxv::program_headers()
+        .iter()
+        .find(|header| header.p_type == PT_TLS) {
+        TLS_IMAGE = TlsImage::new(header, crate::auxv::load_bias());
+    }
+}
+
+fn tls_layout() -> Layout {
+    unsafe { TLS_IMAGE.layout() }
+}
+
+/// Allocate a control block with a fresh copy of the static TLS block below it.
+unsafe fn alloc_tcb() -> *mut PaddedThread {
+    let layout = tls_layout();
+    let base = NAIVE_ALLOC.alloc(layout);
+    if base.is_null() {
+        return core::ptr::null_mut();
+    }
+    let tcb = TLS_IMAGE.thread_pointer(base as usize) as *mut PaddedThread;
     core::ptr::write_bytes(tcb, 0, 1);
-    (*tcb).__thread = (tcb as usize + 8) as *mut Thread;
+    (*tcb).__self = tcb;
+    let block = (tcb as usize - TLS_IMAGE.offset) as *mut u8;
+    core::ptr::copy_nonoverlapping(TLS_IMAGE.init, block, TLS_IMAGE.init_size);
+    core::ptr::write_bytes(block.add(TLS_IMAGE.init_size), 0, TLS_IMAGE.offset - TLS_IMAGE.init_size);
+    let thread = &mut (*tcb).thread;
+    thread.tls_map = core::ptr::slice_from_raw_parts(base, layout.size());
+    thread.tls_block_start = block;
+    tcb
+}
+
+unsafe fn free_tcb(tcb: *mut PaddedThread) {
+    NAIVE_ALLOC.dealloc((*(*tcb).thread.tls_map).as_ptr() as *mut u8, tls_layout());
+}
+
+pub unsafe fn init_main_thread() {
+    init_tls_image();
+    let tcb = alloc_tcb();
     let thread = &mut (*tcb).thread;
     thread.ppid = syscall!(SYS_getpid).unwrap() as u64;
-    thread.tid = syscall!(SYS_gettid).unwrap() as u64;
+    thread.tid = syscall!(SYS_gettid).unwrap() as u32;
     syscall!(SYS_arch_prctl, ARCH_SET_FS, tcb).unwrap();
+    heap_init(&mut thread.heap);
 }
 
-pub unsafe fn thread_self() -> &'static mut Thread {
-    let thread : *mut Thread;
+unsafe fn tcb_self() -> *mut PaddedThread {
+    let tcb : *mut PaddedThread;
     llvm_asm!(
         "mov %fs:0x0, $0" :
-         "=r"(thread)
+         "=r"(tcb)
     );
-    return &mut *thread;
+    tcb
+}
+
+pub unsafe fn thread_self() -> &'static mut Thread {
+    return &mut (*tcb_self()).thread;
+}
+
+/// One past the highest address of the calling thread's stack, 0 if unknown.
+pub(crate) unsafe fn stack_top() -> usize {
+    let stack = thread_self().stack_map;
+    if stack.is_null() {
+        // the main thread runs on the stack set up by the kernel, which
+        // stores the strings `AT_EXECFN` points into near its top
+        crate::auxv::get(crate::auxv::AT_EXECFN).unwrap_or(0)
+    } else {
+        let stack = &*stack;
+        stack.as_ptr() as usize + stack.len()
+    }
 }
 
 pub unsafe fn munmap_self() {
-    NAIVE_ALLOC.dealloc((thread_self() as *mut _ as usize - 8) as *mut u8, Layout::new::<PaddedThread>());
-}
\ No newline at end of file
+    free_thread(tcb_self());
+}
+
+/// Register `dtor(obj)` to run when the calling thread exits.
+///
+/// This is the hook `thread_local!` uses for values that need dropping; the
+/// `dso_symbol` argument only matters for dynamically loaded objects.
+#[cfg_attr(not(test), no_mangle)]
+pub unsafe extern "C" fn __cxa_thread_atexit_impl(dtor: unsafe extern "C" fn(*mut u8),
+                                                  obj: *mut u8,
+                                                  _dso_symbol: *mut u8) -> i32 {
+    let thread = thread_self();
+    thread.tls_dtor_list = Box::into_raw(Box::new(TlsDtor {
+        dtor,
+        obj,
+        next: thread.tls_dtor_list,
+    }));
+    0
+}
+
+/// Run the calling thread's TLS destructors, most recently registered first.
+/// Destructors may register further destructors; those run as well.
+pub unsafe fn run_tls_dtors() {
+    let thread = thread_self();
+    while !thread.tls_dtor_list.is_null() {
+        let node = Box::from_raw(thread.tls_dtor_list);
+        thread.tls_dtor_list = node.next;
+        (node.dtor)(node.obj);
+    }
+}
+
+/// Release a thread's stack, control block and TLS. The thread must be gone,
+/// or be the caller on a stack it does not own (the main thread).
+unsafe fn free_thread(tcb: *mut PaddedThread) {
+    // the main thread has no stack map: a null slice pointer
+    let stack_map = (*tcb).thread.stack_map;
+    if !stack_map.is_null() {
+        let stack = &*stack_map;
+        match syscall!(SYS_munmap, stack.as_ptr(), stack.len()) {
+            _ => ()
+        }
+    }
+    free_tcb(tcb);
+}
+
+struct Packet<T> {
+    result: Option<T>,
+}
+
+pub struct JoinHandle<T> {
+    tcb: *mut PaddedThread,
+    packet: *mut Packet<T>,
+    _marker: PhantomData<T>,
+}
+
+unsafe impl<T: Send> Send for JoinHandle<T> {}
+
+impl<T> JoinHandle<T> {
+    /// Block until the thread exits and return the value its closure produced.
+    pub fn join(self) -> T {
+        unsafe {
+            self.wait_exit();
+            let tcb = self.tcb;
+            let packet = self.packet;
+            core::mem::forget(self);
+            free_thread(tcb);
+            Box::from_raw(packet).result.expect("thread exited without a result")
+        }
+    }
+
+    /// Let the thread run on its own; it releases its stack, control block and
+    /// result when it exits. Dropping the handle has the same effect.
+    pub fn detach(self) {
+        drop(self)
+    }
+
+    /// The kernel zeroes `Thread::tid` and wakes the futex on it only after the
+    /// child is completely off its stack, so both can be released afterwards.
+    unsafe fn wait_exit(&self) {
+        let tid = &*(&(*self.tcb).thread.tid as *const u32 as *const AtomicU32);
+        loop {
+            let current = tid.load(Ordering::Acquire);
+            if current == 0 {
+                break;
+            }
+            futex_wait_shared(tid, current);
+        }
+    }
+}
+
+impl<T> Drop for JoinHandle<T> {
+    fn drop(&mut self) {
+        unsafe {
+            let state = &(*self.tcb).thread.join_state;
+            if state.compare_exchange(RUNNING, DETACHED, Ordering::AcqRel, Ordering::Acquire).is_err() {
+                // the child already finished and expects to be joined
+                self.wait_exit();
+                free_thread(self.tcb);
+                drop(Box::from_raw(self.packet));
+            }
+        }
+    }
+}
+
+/// Spawn a thread running `f` on a freshly mapped stack.
+///
+/// The child shares the address space, files and signal handlers with the
+/// caller and gets its own `PaddedThread` installed as its thread pointer.
+/// The kernel writes the child's tid into `Thread::tid` before either side
+/// runs and clears it again once the child has exited.
+pub fn spawn<F, T>(f: F) -> JoinHandle<T>
+    where F: FnOnce() -> T + Send + 'static,
+          T: Send + 'static {
+    let packet = Box::into_raw(Box::new(Packet { result: None }));
+    let their_packet = packet as usize;
+    let main: Box<dyn FnOnce()> = Box::new(move || unsafe {
+        let packet = their_packet as *mut Packet<T>;
+        (*packet).result = Some(f());
+        let state = &thread_self().join_state;
+        if state.compare_exchange(RUNNING, FINISHED, Ordering::AcqRel, Ordering::Acquire).is_err() {
+            // detached: nobody is going to collect the result
+            drop(Box::from_raw(packet));
+        }
+    });
+    let main = Box::into_raw(Box::new(main));
+    unsafe {
+        let stack_map = map_stack(DEFAULT_STACK_SIZE);
+        if stack_map.is_null() {
+            panic!("failed to map a thread stack");
+        }
+        let tcb = alloc_tcb();
+        if tcb.is_null() {
+            panic!("failed to allocate a thread control block");
+        }
+        let thread = &mut (*tcb).thread;
+        thread.ppid = thread_self().ppid;
+        thread.stack_map = stack_map;
+        let stack = &*stack_map;
+        let stack_top = (stack.as_ptr() as usize + stack.len()) & !15;
+        let tid_word = &mut thread.tid as *mut u32;
+        let tid = clone(
+            CLONE_THREAD_FLAGS,
+            stack_top as *mut u8,
+            tid_word,
+            tid_word,
+            tcb as u64,
+            thread_start,
+            main as *mut u8,
+        );
+        if tid < 0 {
+            match syscall!(SYS_munmap, stack.as_ptr(), stack.len()) {
+                _ => ()
+            }
+            free_tcb(tcb);
+            drop(Box::from_raw(main));
+            drop(Box::from_raw(packet));
+            panic!("failed to spawn thread: errno {}", -tid);
+        }
+        JoinHandle {
+            tcb,
+            packet,
+            _marker: PhantomData,
+        }
+    }
+}
+
+/// Map `size` bytes of stack with an inaccessible guard page below it.
+unsafe fn map_stack(size: usize) -> *const [u8] {
+    let page = crate::auxv::page_size();
+    let size = (size + page - 1) & !(page - 1);
+    let base = match syscall!(
+        SYS_mmap,
+        0,
+        size + page,
+        flag::PROT_READ | flag::PROT_WRITE,
+        flag::MAP_PRIVATE | flag::MAP_ANON | flag::MAP_STACK,
+        -1i64,
+        0
+    ) {
+        Ok(base) => base as *mut u8,
+        Err(_) => return core::ptr::slice_from_raw_parts(core::ptr::null(), 0)
+    };
+    if syscall!(SYS_mprotect, base, page, flag::PROT_NONE).is_err() {
+        match syscall!(SYS_munmap, base, size + page) {
+            _ => ()
+        }
+        return core::ptr::slice_from_raw_parts(core::ptr::null(), 0);
+    }
+    core::ptr::slice_from_raw_parts(base, size + page)
+}
+
+/// Raw `clone` that starts the child at `entry(arg)`.
+///
+/// The child comes back from the syscall on `stack` with none of the
+/// caller's frame reachable, so it must not return into Rust code here:
+/// it jumps straight to `entry`, which never returns.
+unsafe fn clone(flags: u64, stack: *mut u8, ptid: *mut u32, ctid: *mut u32, tls: u64,
+                entry: unsafe extern "C" fn(*mut u8) -> !, arg: *mut u8) -> i64 {
+    let ret: i64;
+    asm!(
+        "syscall",
+        "test rax, rax",
+        "jnz 2f",
+        "xor ebp, ebp",
+        "mov rdi, r13",
+        "call r12",
+        "ud2",
+        "2:",
+        inlateout("rax") SYS_clone as i64 => ret,
+        in("rdi") flags,
+        in("rsi") stack,
+        in("rdx") ptid,
+        in("r10") ctid,
+        in("r8") tls,
+        in("r12") entry,
+        in("r13") arg,
+        lateout("rcx") _,
+        lateout("r11") _,
+    );
+    ret
+}
+
+unsafe extern "C" fn thread_start(main: *mut u8) -> ! {
+    heap_init(&mut thread_self().heap);
+    let main = Box::from_raw(main as *mut Box<dyn FnOnce()>);
+    main();
+    run_tls_dtors();
+    let thread = thread_self();
+    heap_done(&mut thread.heap);
+    if thread.join_state.load(Ordering::Acquire) == DETACHED {
+        let stack = thread.stack_map;
+        // from here on a signal handler would run without a control block or
+        // TLS, and then without a stack
+        let mask = !0u64;
+        syscall!(SYS_rt_sigprocmask, SIG_BLOCK, &mask as *const u64, 0, 8).unwrap();
+        // the control block is about to go away, so the kernel must not clear
+        // the tid in it on exit, and nothing may reach it through %fs anymore
+        syscall!(SYS_set_tid_address, 0).unwrap();
+        free_tcb(tcb_self());
+        unmap_and_exit(stack);
+    }
+    syscall!(SYS_exit, 0).unwrap();
+    core::hint::unreachable_unchecked()
+}
+
+/// Unmap the stack we are running on and exit the thread.
+///
+/// Once the `munmap` returns there is no stack, so both syscalls are issued
+/// back to back from registers. The caller must have blocked all signals: a
+/// handler would otherwise be delivered onto the unmapped stack.
+unsafe fn unmap_and_exit(stack: *const [u8]) -> ! {
+    let stack = &*stack;
+    asm!(
+        "syscall",
+        "mov rax, r12",
+        "xor edi, edi",
+        "syscall",
+        "ud2",
+        in("rax") SYS_munmap as i64,
+        in("rdi") stack.as_ptr(),
+        in("rsi") stack.len(),
+        in("r12") SYS_exit as i64,
+        options(noreturn)
+    );
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn tls_header(vaddr: u64, filesz: u64, memsz: u64, align: u64) -> Elf64Phdr {
+        Elf64Phdr {
+            p_type: PT_TLS,
+            p_flags: 0,
+            p_offset: 0,
+            p_vaddr: vaddr,
+            p_paddr: vaddr,
+            p_filesz: filesz,
+            p_memsz: memsz,
+            p_align: align,
+        }
+    }
+
+    #[test]
+    fn test_tls_layout() {
+        let bias = 0x40_0000;
+        let cases = [
+            // vaddr, filesz, memsz, align
+            (0x2000, 0, 0, 1),
+            (0x2000, 8, 8, 8),
+            (0x2000, 12, 40, 16),
+            (0x2008, 4, 100, 8),
+            (0x2010, 24, 24, 64),
+            (0x2130, 100, 300, 4096),
+            // p_vaddr below its own alignment, as linkers leave it for .tdata
+            (0x2004, 4, 12, 8),
+            (0x2018, 16, 72, 32),
+            (0x2fc0, 0, 64, 128),
+        ];
+        for &(vaddr, filesz, memsz, align) in cases.iter() {
+            let image = TlsImage::new(&tls_header(vaddr, filesz, memsz, align), bias);
+            let layout = image.layout();
+            assert!(layout.align() >= align as usize);
+            assert!(layout.align() >= core::mem::align_of::<PaddedThread>());
+            assert_eq!(image.init as usize, bias + vaddr as usize);
+            assert!(image.offset >= memsz as usize && image.offset < memsz as usize + align as usize);
+            // every allocation the allocator might return for the layout
+            for base in (0..2 * layout.align()).step_by(core::mem::align_of::<usize>()) {
+                let base = 0x1000_0000 + base;
+                let tp = image.thread_pointer(base);
+                let block = tp - image.offset;
+                assert_eq!(tp % layout.align(), 0);
+                assert!(block >= base);
+                assert!(tp + core::mem::size_of::<PaddedThread>() <= base + layout.size());
+                // %fs-relative offsets the linker computed hold for this block
+                assert_eq!(block % align as usize, image.init as usize % align as usize);
+                assert!(block + memsz as usize <= tp);
+            }
+        }
+    }
+
+    #[test]
+    fn test_tls_no_segment() {
+        let image = TlsImage { init: core::ptr::null(), init_size: 0, size: 0, align: 1, offset: 0 };
+        let layout = image.layout();
+        assert_eq!(layout.align(), core::mem::align_of::<PaddedThread>());
+        assert_eq!(image.thread_pointer(0x1000), 0x1000);
+        assert!(layout.size() >= core::mem::size_of::<PaddedThread>());
+    }
+}
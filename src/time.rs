@@ -0,0 +1,39 @@
+#![allow(unused)]
+
+use core::time::Duration;
+
+pub const CLOCK_REALTIME : i32 = 0;
+pub const CLOCK_MONOTONIC : i32 = 1;
+pub const CLOCK_PROCESS_CPUTIME_ID : i32 = 2;
+pub const CLOCK_THREAD_CPUTIME_ID : i32 = 3;
+pub const CLOCK_MONOTONIC_RAW : i32 = 4;
+pub const CLOCK_REALTIME_COARSE : i32 = 5;
+pub const CLOCK_MONOTONIC_COARSE : i32 = 6;
+pub const CLOCK_BOOTTIME : i32 = 7;
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Timespec {
+    pub tv_sec: i64,
+    pub tv_nsec: i64,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Timeval {
+    pub tv_sec: i64,
+    pub tv_usec: i64,
+}
+
+impl Timespec {
+    pub fn from_duration(duration: Duration) -> Self {
+        Timespec {
+            tv_sec: duration.as_secs() as i64,
+            tv_nsec: duration.subsec_nanos() as i64,
+        }
+    }
+
+    pub fn as_duration(&self) -> Duration {
+        Duration::new(self.tv_sec as u64, self.tv_nsec as u32)
+    }
+}
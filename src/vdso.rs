@@ -0,0 +1,232 @@
+#![allow(unused)]
+
+use syscalls::*;
+
+use crate::elf::*;
+use crate::time::{Timespec, Timeval};
+
+type ClockGettime = unsafe extern "C" fn(i32, *mut Timespec) -> i32;
+type Gettimeofday = unsafe extern "C" fn(*mut Timeval, *mut u8) -> i32;
+type Time = unsafe extern "C" fn(*mut i64) -> i64;
+type Getcpu = unsafe extern "C" fn(*mut u32, *mut u32, *mut u8) -> i32;
+
+// Resolved entry points; zero means the symbol is missing and the raw
+// syscall is used instead.
+static mut CLOCK_GETTIME : usize = 0;
+static mut GETTIMEOFDAY : usize = 0;
+static mut TIME : usize = 0;
+static mut GETCPU : usize = 0;
+
+/// Locate the vDSO through `AT_SYSINFO_EHDR` and resolve the symbols we use.
+pub unsafe fn init() {
+    if let Some(base) = crate::auxv::sysinfo_ehdr() {
+        init_from(base);
+    }
+}
+
+pub unsafe fn init_from(base: usize) {
+    let image = match Image::parse(base) {
+        Some(image) => image,
+        None => return
+    };
+    CLOCK_GETTIME = image.lookup(b"__vdso_clock_gettime").unwrap_or(0);
+    GETTIMEOFDAY = image.lookup(b"__vdso_gettimeofday").unwrap_or(0);
+    TIME = image.lookup(b"__vdso_time").unwrap_or(0);
+    GETCPU = image.lookup(b"__vdso_getcpu").unwrap_or(0);
+}
+
+struct Image {
+    bias: usize,
+    symtab: *const Elf64Sym,
+    strtab: *const u8,
+    count: usize,
+}
+
+impl Image {
+    unsafe fn parse(base: usize) -> Option<Self> {
+        let ehdr = &*(base as *const Elf64Ehdr);
+        if ehdr.e_ident[..4] != ELFMAG || ehdr.e_ident[EI_CLASS] != ELFCLASS64 {
+            return None;
+        }
+        let phdrs = core::slice::from_raw_parts(
+            (base + ehdr.e_phoff as usize) as *const Elf64Phdr,
+            ehdr.e_phnum as usize,
+        );
+        // the image is mapped as a whole, so file offsets are valid addresses
+        // relative to `base`; symbol values are relative to the load address
+        let mut bias = None;
+        let mut dynamic = None;
+        for phdr in phdrs {
+            match phdr.p_type {
+                PT_LOAD if bias.is_none() => {
+                    bias = Some((base + phdr.p_offset as usize).wrapping_sub(phdr.p_vaddr as usize));
+                }
+                PT_DYNAMIC => {
+                    dynamic = Some((base + phdr.p_offset as usize) as *const Elf64Dyn);
+                }
+                _ => ()
+            }
+        }
+        let bias = bias?;
+        let mut entry = dynamic?;
+        let mut symtab = 0;
+        let mut strtab = 0;
+        let mut hash = 0;
+        let mut gnu_hash = 0;
+        while (*entry).d_tag != DT_NULL {
+            let address = bias.wrapping_add((*entry).d_val as usize);
+            match (*entry).d_tag {
+                DT_SYMTAB => symtab = address,
+                DT_STRTAB => strtab = address,
+                DT_HASH => hash = address,
+                DT_GNU_HASH => gnu_hash = address,
+                _ => ()
+            }
+            entry = entry.add(1);
+        }
+        if symtab == 0 || strtab == 0 {
+            return None;
+        }
+        let count = if hash != 0 {
+            // nchain equals the number of symbols
+            *(hash as *const u32).add(1) as usize
+        } else if gnu_hash != 0 {
+            gnu_hash_count(gnu_hash as *const u32)
+        } else {
+            return None;
+        };
+        Some(Image {
+            bias,
+            symtab: symtab as *const Elf64Sym,
+            strtab: strtab as *const u8,
+            count,
+        })
+    }
+
+    unsafe fn lookup(&self, name: &[u8]) -> Option<usize> {
+        for i in 0..self.count {
+            let sym = &*self.symtab.add(i);
+            if sym.st_shndx == SHN_UNDEF || sym.symbol_type() != STT_FUNC {
+                continue;
+            }
+            if sym.binding() != STB_GLOBAL && sym.binding() != STB_WEAK {
+                continue;
+            }
+            if crate::env::c_str(self.strtab.add(sym.st_name as usize)) == name {
+                return Some(self.bias.wrapping_add(sym.st_value as usize));
+            }
+        }
+        None
+    }
+}
+
+/// DT_GNU_HASH carries no symbol count, so walk to the end of the longest chain.
+unsafe fn gnu_hash_count(table: *const u32) -> usize {
+    let nbuckets = *table as usize;
+    let symoffset = *table.add(1) as usize;
+    let bloom_size = *table.add(2) as usize;
+    let buckets = table.add(4 + bloom_size * 2);
+    let chains = buckets.add(nbuckets);
+    let mut last = 0;
+    for i in 0..nbuckets {
+        last = last.max(*buckets.add(i) as usize);
+    }
+    if last < symoffset {
+        return symoffset;
+    }
+    while *chains.add(last - symoffset) & 1 == 0 {
+        last += 1;
+    }
+    last + 1
+}
+
+pub fn clock_gettime(clock: i32) -> Result<Timespec, i64> {
+    let mut ts = Timespec::default();
+    unsafe {
+        if CLOCK_GETTIME != 0 {
+            let f: ClockGettime = core::mem::transmute(CLOCK_GETTIME);
+            match f(clock, &mut ts) {
+                0 => Ok(ts),
+                err => Err(-err as i64)
+            }
+        } else {
+            syscall!(SYS_clock_gettime, clock, &mut ts as *mut Timespec).map(|_| ts)
+        }
+    }
+}
+
+pub fn gettimeofday() -> Result<Timeval, i64> {
+    let mut tv = Timeval::default();
+    unsafe {
+        if GETTIMEOFDAY != 0 {
+            let f: Gettimeofday = core::mem::transmute(GETTIMEOFDAY);
+            match f(&mut tv, core::ptr::null_mut()) {
+                0 => Ok(tv),
+                err => Err(-err as i64)
+            }
+        } else {
+            syscall!(SYS_gettimeofday, &mut tv as *mut Timeval, 0).map(|_| tv)
+        }
+    }
+}
+
+/// Seconds since the epoch.
+pub fn time() -> i64 {
+    unsafe {
+        if TIME != 0 {
+            let f: Time = core::mem::transmute(TIME);
+            f(core::ptr::null_mut())
+        } else {
+            syscall!(SYS_time, 0).unwrap_or(0)
+        }
+    }
+}
+
+/// Returns `(cpu, node)` for the calling thread.
+pub fn getcpu() -> Result<(u32, u32), i64> {
+    let mut cpu = 0u32;
+    let mut node = 0u32;
+    unsafe {
+        if GETCPU != 0 {
+            let f: Getcpu = core::mem::transmute(GETCPU);
+            match f(&mut cpu, &mut node, core::ptr::null_mut()) {
+                0 => Ok((cpu, node)),
+                err => Err(-err as i64)
+            }
+        } else {
+            syscall!(SYS_getcpu, &mut cpu as *mut u32, &mut node as *mut u32, 0).map(|_| (cpu, node))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::time::*;
+
+    extern "C" {
+        fn getauxval(key: u64) -> u64;
+    }
+
+    #[test]
+    fn test_vdso() {
+        unsafe {
+            init_from(getauxval(crate::auxv::AT_SYSINFO_EHDR as u64) as usize);
+            assert_ne!(CLOCK_GETTIME, 0);
+            assert_ne!(GETTIMEOFDAY, 0);
+            assert_ne!(TIME, 0);
+            assert_ne!(GETCPU, 0);
+        }
+        let fast = clock_gettime(CLOCK_MONOTONIC).unwrap();
+        let mut slow = Timespec::default();
+        unsafe {
+            syscall!(SYS_clock_gettime, CLOCK_MONOTONIC, &mut slow as *mut Timespec).unwrap();
+        }
+        assert!(fast <= slow);
+        assert!(clock_gettime(-1).is_err());
+        let tv = gettimeofday().unwrap();
+        assert!((tv.tv_sec - time()).abs() <= 1);
+        let (cpu, _) = getcpu().unwrap();
+        assert!(cpu < 4096);
+    }
+}
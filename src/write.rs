@@ -1,7 +1,7 @@
 use syscalls::*;
 use crate::sync::Futex;
 use core::cell::UnsafeCell;
-use core::sync::atomic::AtomicU64;
+use core::sync::atomic::{AtomicU32, AtomicU64};
 
 pub struct Writer {
     fd: u64,
@@ -20,6 +20,7 @@ impl Writer {
 #[no_mangle]
 pub static WRITER: Futex<Writer> = Futex {
     _flag: AtomicU64::new(0),
+    _elision: AtomicU32::new(0),
     item: UnsafeCell::new(Writer {
         fd: 1,
     })
@@ -28,6 +29,7 @@ pub static WRITER: Futex<Writer> = Futex {
 #[no_mangle]
 pub static EWRITER: Futex<Writer> = Futex {
     _flag: AtomicU64::new(0),
+    _elision: AtomicU32::new(0),
     item: UnsafeCell::new(Writer {
         fd: 2,
     })